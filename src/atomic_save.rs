@@ -0,0 +1,116 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::ChangeMessage;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long a removed file waits for a replacement before the removal is reported.
+const REPLACEMENT_WINDOW: Duration = Duration::from_millis(100);
+
+/// File names editors use for temporary, backup and swap files while saving.
+fn is_temporary(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+
+    // vim checks that it may create files with `4913`, then `5036` and so on
+    let is_vim_probe = name
+        .parse::<u32>()
+        .is_ok_and(|number| number >= 4913 && (number - 4913) % 123 == 0);
+    let is_swap = name.starts_with('.')
+        && [".swp", ".swo", ".swx"]
+            .iter()
+            .any(|extension| name.ends_with(extension));
+
+    is_vim_probe
+        || is_swap
+        || name.ends_with('~')
+        || name.starts_with(".#")
+        || (name.starts_with('#') && name.ends_with('#'))
+        || name.contains("___jb_tmp___")
+        || name.contains("___jb_old___")
+        || name.starts_with(".goutputstream-")
+}
+
+/// Recognizes saves that replace a file instead of writing to it, and reports them as a
+/// single [`ChangeMessage::Modified`] of the saved file:
+///
+/// - writing a temporary file and renaming it over the original,
+/// - moving the original aside, or deleting it, and creating it again.
+///
+/// Changes to the temporary files themselves are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicSaveDetector {
+    /// Files that were removed or moved aside, and may be about to be replaced.
+    removed: Vec<(PathBuf, Instant)>,
+    /// Files that were just replaced. Writing their new contents is part of the same save.
+    replaced: Vec<(PathBuf, Instant)>,
+}
+
+impl AtomicSaveDetector {
+    pub(crate) fn push(
+        &mut self,
+        messages: Vec<ChangeMessage>,
+        now: Instant,
+    ) -> Vec<ChangeMessage> {
+        self.replaced
+            .retain(|(_, since)| now < *since + REPLACEMENT_WINDOW);
+
+        let mut passed = Vec::new();
+        for message in messages {
+            match message {
+                ChangeMessage::Renamed { from, to } => {
+                    match (is_temporary(&from), is_temporary(&to)) {
+                        (true, true) => {}
+                        (true, false) => {
+                            // the original is overwritten without an event of its own
+                            self.removed.retain(|(removed, _)| *removed != to);
+                            passed.push(ChangeMessage::Modified(to));
+                        }
+                        (false, true) => self.removed.push((from, now)),
+                        (false, false) => passed.push(ChangeMessage::Renamed { from, to }),
+                    }
+                }
+                ChangeMessage::Removed(path) if !is_temporary(&path) => {
+                    self.removed.push((path, now));
+                }
+                ChangeMessage::Created(path) if !is_temporary(&path) => {
+                    let before = self.removed.len();
+                    self.removed.retain(|(removed, _)| *removed != path);
+                    if self.removed.len() == before {
+                        passed.push(ChangeMessage::Created(path));
+                    } else {
+                        self.replaced.push((path.clone(), now));
+                        passed.push(ChangeMessage::Modified(path));
+                    }
+                }
+                ChangeMessage::Modified(path)
+                    if self.replaced.iter().any(|(replaced, _)| *replaced == path) => {}
+                message if is_temporary(message.path()) => {}
+                message => passed.push(message),
+            }
+        }
+        passed
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.removed
+            .iter()
+            .map(|(_, since)| *since + REPLACEMENT_WINDOW)
+            .min()
+    }
+
+    /// Removed files that were not replaced in time are reported as removed.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<ChangeMessage> {
+        let (expired, removed) = std::mem::take(&mut self.removed)
+            .into_iter()
+            .partition(|(_, since)| *since + REPLACEMENT_WINDOW <= now);
+        self.removed = removed;
+        expired
+            .into_iter()
+            .map(|(path, _): (PathBuf, Instant)| ChangeMessage::Removed(path))
+            .collect()
+    }
+}
@@ -0,0 +1,459 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::event_loop::LoopInput;
+use crate::roots::directories_within;
+use crate::{FileWatcherError, WatchRoot, map_notify_error_to_file_watcher_error};
+use notify::event::{DataChange, MetadataKind, ModifyKind};
+use notify::{
+    Config, ErrorKind, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode,
+    Result as NotifyResult, Watcher,
+};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+/// Filesystem types that are known to not deliver native change events.
+const POLL_ONLY_FILESYSTEMS: [&str; 14] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "virtiofs",
+    "fuse",
+    "fuseblk",
+    "sshfs",
+    "vboxsf",
+    "vmhgfs",
+    "grpcfuse",
+    "fakeowner",
+];
+
+/// How the filesystem is observed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The native event API of the platform, like inotify or `FSEvents`.
+    #[default]
+    Native,
+    /// Scans the tree every `interval`. If `compare_contents` is set, files are hashed
+    /// so modifications that keep the modification time are noticed as well.
+    Poll {
+        interval: Duration,
+        compare_contents: bool,
+    },
+    /// Native events, falling back to polling if the native watcher can not be set up,
+    /// or if a root is on a network or FUSE filesystem.
+    Auto {
+        interval: Duration,
+        compare_contents: bool,
+    },
+}
+
+/// What to do when the system limit on native watches is reached.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WatchLimitPolicy {
+    /// Fail with [`FileWatcherError::TooManyWatches`].
+    #[default]
+    Fail,
+    /// Keep the native watches that fit, and scan the remaining subtrees every `interval`.
+    PollRemaining { interval: Duration },
+}
+
+/// A root that did not fit within the native watch limit.
+#[derive(Debug, Default)]
+struct DegradedWatch {
+    native_dirs: Vec<PathBuf>,
+    polled_dirs: Vec<PathBuf>,
+}
+
+/// The watcher that is actually running. Native events, polling, or native events
+/// with polling for the parts of the tree that did not fit within the watch limit.
+#[derive(Debug)]
+pub(crate) struct BackendWatcher {
+    native: Option<RecommendedWatcher>,
+    poll: Option<PollWatcher>,
+    poll_config: Config,
+    loop_sender: mpsc::Sender<LoopInput>,
+    watch_limit_policy: WatchLimitPolicy,
+    degraded: BTreeMap<PathBuf, DegradedWatch>,
+    /// A lower limit than the system's for the native watches of degraded roots, counted down
+    /// as they are added. Lets the degraded mode be tested without exhausting the real limit.
+    #[cfg(test)]
+    native_watches_left: Option<usize>,
+}
+
+impl BackendWatcher {
+    /// Returns true if only polling is used.
+    #[must_use]
+    pub(crate) const fn is_polling(&self) -> bool {
+        self.native.is_none()
+    }
+
+    /// Returns true if the native watch limit was reached, and parts of the tree are polled instead.
+    #[must_use]
+    pub(crate) fn is_degraded(&self) -> bool {
+        !self.degraded.is_empty()
+    }
+
+    /// The directories whose subtrees are polled because the native watch limit was reached.
+    #[must_use]
+    pub(crate) fn polled_paths(&self) -> Vec<PathBuf> {
+        self.degraded
+            .values()
+            .flat_map(|degraded| degraded.polled_dirs.iter().cloned())
+            .collect()
+    }
+
+    /// # Errors
+    ///
+    pub(crate) fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> NotifyResult<()> {
+        let Some(native) = &mut self.native else {
+            return self.poll_watcher()?.watch(path, recursive_mode);
+        };
+
+        match native.watch(path, recursive_mode) {
+            Err(e) if matches!(e.kind, ErrorKind::MaxFilesWatch) => {
+                if self.watch_limit_policy == WatchLimitPolicy::Fail {
+                    return Err(e);
+                }
+                // the backend may have added some of the watches before running out,
+                // and unwatching the root does not remove those
+                for dir in directories_within(path, usize::MAX) {
+                    let _ = native.unwatch(&dir);
+                }
+                warn!(path = ?path, "native watch limit reached, polling part of the tree");
+                self.watch_degraded(path, recursive_mode)
+            }
+            result => result,
+        }
+    }
+
+    /// # Errors
+    ///
+    pub(crate) fn unwatch(&mut self, path: &Path) -> NotifyResult<()> {
+        if let Some(degraded) = self.degraded.remove(path) {
+            for dir in &degraded.native_dirs {
+                if let Some(native) = &mut self.native {
+                    let _ = native.unwatch(dir);
+                }
+            }
+            for dir in &degraded.polled_dirs {
+                if let Some(poll) = &mut self.poll {
+                    let _ = poll.unwatch(dir);
+                }
+            }
+            return Ok(());
+        }
+
+        match &mut self.native {
+            Some(native) => native.unwatch(path),
+            None => self.poll_watcher()?.unwatch(path),
+        }
+    }
+
+    /// Gives a directory created below a degraded root a watch of its own, since
+    /// the native watches of degraded roots are not recursive.
+    pub(crate) fn cover_new_directory(&mut self, dir: &Path) {
+        let Some(root) = self
+            .degraded
+            .iter()
+            .find(|(root, degraded)| {
+                dir.starts_with(root)
+                    && !degraded
+                        .polled_dirs
+                        .iter()
+                        .any(|polled| dir.starts_with(polled))
+            })
+            .map(|(root, _)| root.clone())
+        else {
+            return;
+        };
+
+        let mut expansion = DegradedWatch::default();
+        if let Err(e) = self.expand(dir, &mut expansion) {
+            warn!(error = ?e, path = ?dir, "Failed to watch new directory");
+        }
+        if let Some(degraded) = self.degraded.get_mut(&root) {
+            degraded.native_dirs.extend(expansion.native_dirs);
+            degraded.polled_dirs.extend(expansion.polled_dirs);
+        }
+    }
+
+    fn watch_degraded(&mut self, path: &Path, recursive_mode: RecursiveMode) -> NotifyResult<()> {
+        let mut degraded = DegradedWatch::default();
+        let result = match recursive_mode {
+            RecursiveMode::Recursive => self.expand(path, &mut degraded),
+            RecursiveMode::NonRecursive => {
+                degraded.polled_dirs.push(path.to_path_buf());
+                self.poll_watcher()?
+                    .watch(path, RecursiveMode::NonRecursive)
+            }
+        };
+        self.degraded.insert(path.to_path_buf(), degraded);
+        result
+    }
+
+    /// Watches the directories below `dir` one by one, breadth first, so the shallow
+    /// directories get native watches. Once the limit is hit, the subtrees of the
+    /// directories that are left are polled.
+    fn expand(&mut self, dir: &Path, degraded: &mut DegradedWatch) -> NotifyResult<()> {
+        let mut queue = VecDeque::from([dir.to_path_buf()]);
+        while let Some(next) = queue.pop_front() {
+            let native_result = match &mut self.native {
+                #[cfg(test)]
+                Some(_) if self.native_watches_left == Some(0) => {
+                    Err(notify::Error::new(ErrorKind::MaxFilesWatch))
+                }
+                Some(native) => native.watch(&next, RecursiveMode::NonRecursive),
+                None => Err(notify::Error::new(ErrorKind::MaxFilesWatch)),
+            };
+            match native_result {
+                Ok(()) => {
+                    #[cfg(test)]
+                    if let Some(left) = &mut self.native_watches_left {
+                        *left -= 1;
+                    }
+                    degraded.native_dirs.push(next.clone());
+                    queue.extend(subdirectories(&next));
+                }
+                Err(e) if matches!(e.kind, ErrorKind::MaxFilesWatch) => {
+                    queue.push_front(next);
+                    let poll = self.poll_watcher()?;
+                    for remaining in queue.drain(..) {
+                        poll.watch(&remaining, RecursiveMode::Recursive)?;
+                        degraded.polled_dirs.push(remaining);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_watcher(&mut self) -> NotifyResult<&mut PollWatcher> {
+        let poll = match self.poll.take() {
+            Some(poll) => poll,
+            None => self.create_poll_watcher()?,
+        };
+        Ok(self.poll.insert(poll))
+    }
+
+    fn create_poll_watcher(&self) -> NotifyResult<PollWatcher> {
+        let event_sender = self.loop_sender.clone();
+        PollWatcher::new(
+            move |res: NotifyResult<Event>| {
+                let _ = event_sender.send(LoopInput::Event(res.map(normalize_poll_event)));
+            },
+            self.poll_config,
+        )
+    }
+}
+
+fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Creates the watcher for `backend` and registers the watches for `roots`.
+pub(crate) fn start_backend(
+    backend: Backend,
+    watch_limit_policy: WatchLimitPolicy,
+    roots: &[WatchRoot],
+    loop_sender: &mpsc::Sender<LoopInput>,
+) -> Result<BackendWatcher, FileWatcherError> {
+    let start = |native: bool, poll_config: Config| {
+        start_watcher(native, poll_config, watch_limit_policy, roots, loop_sender)
+    };
+
+    match backend {
+        Backend::Native => start(true, Config::default()),
+        Backend::Poll {
+            interval,
+            compare_contents,
+        } => start(false, poll_config(interval, compare_contents)),
+        Backend::Auto {
+            interval,
+            compare_contents,
+        } => {
+            let poll_config = poll_config(interval, compare_contents);
+            if let Some(root) = roots
+                .iter()
+                .find(|root| is_poll_only_filesystem(&root.path))
+            {
+                debug!(path = ?root.path, "root is on a filesystem without native events, polling");
+                return start(false, poll_config);
+            }
+            match start(true, poll_config) {
+                Err(FileWatcherError::PathNotFound(path)) => {
+                    Err(FileWatcherError::PathNotFound(path))
+                }
+                Err(e) => {
+                    warn!(error = ?e, "native watcher could not be set up, falling back to polling");
+                    start(false, poll_config)
+                }
+                started => started,
+            }
+        }
+    }
+}
+
+fn poll_config(interval: Duration, compare_contents: bool) -> Config {
+    Config::default()
+        .with_poll_interval(interval)
+        .with_compare_contents(compare_contents)
+}
+
+fn start_watcher(
+    native: bool,
+    poll_config: Config,
+    watch_limit_policy: WatchLimitPolicy,
+    roots: &[WatchRoot],
+    loop_sender: &mpsc::Sender<LoopInput>,
+) -> Result<BackendWatcher, FileWatcherError> {
+    // a poll watcher next to the native one only covers what exceeds the watch limit
+    let poll_config = match watch_limit_policy {
+        WatchLimitPolicy::PollRemaining { interval } if native => {
+            poll_config.with_poll_interval(interval)
+        }
+        _ => poll_config,
+    };
+    let native = if native {
+        let event_sender = loop_sender.clone();
+        let watcher = notify::recommended_watcher(move |res: NotifyResult<Event>| {
+            // The event loop is gone only when the watcher itself is being dropped
+            let _ = event_sender.send(LoopInput::Event(res));
+        })
+        .map_err(|e| {
+            error!(error = ?e, roots = ?roots, "Failed to initialize watcher");
+            map_notify_error_to_file_watcher_error(e, first_root_path(roots))
+        })?;
+        Some(watcher)
+    } else {
+        None
+    };
+
+    let mut watcher = BackendWatcher {
+        native,
+        poll: None,
+        poll_config,
+        loop_sender: loop_sender.clone(),
+        watch_limit_policy,
+        degraded: BTreeMap::new(),
+        #[cfg(test)]
+        native_watches_left: None,
+    };
+    crate::update_backend_watches(&mut watcher, &[], roots)?;
+    Ok(watcher)
+}
+
+/// The poll watcher reports a newer modification time as a metadata change.
+/// For files that is how a write shows up, so report it like the native backends do.
+fn normalize_poll_event(mut event: Event) -> Event {
+    if event.kind == EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+        && event.paths.iter().all(|path| !path.is_dir())
+    {
+        event.kind = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+    }
+    event
+}
+
+fn first_root_path(roots: &[WatchRoot]) -> &Path {
+    roots
+        .first()
+        .map_or(Path::new(""), |root| root.path.as_path())
+}
+
+/// Looks up the filesystem type of the mount that `path` is on. Only implemented for Linux.
+fn is_poll_only_filesystem(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    let Ok(mounts) = std::fs::read_to_string("/proc/self/mounts") else {
+        return false;
+    };
+
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _device = fields.next()?;
+            let mount_point = unescape_mount_point(fields.next()?);
+            let filesystem_type = fields.next()?;
+            Some((mount_point, filesystem_type))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.len())
+        .is_some_and(|(_, filesystem_type)| {
+            POLL_ONLY_FILESYSTEMS.contains(&filesystem_type) || filesystem_type.starts_with("fuse.")
+        })
+}
+
+/// `/proc/self/mounts` escapes spaces and a few other characters as octal, like `\040`.
+fn unescape_mount_point(escaped: &str) -> String {
+    let mut unescaped = String::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let octal = rest.get(index + 1..index + 4);
+        match octal.and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(char::from(byte));
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shallow_directories_are_watched_natively_and_the_rest_is_polled() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+        let (loop_sender, _loop_receiver) = mpsc::channel();
+        let policy = WatchLimitPolicy::PollRemaining {
+            interval: Duration::from_millis(50),
+        };
+        let mut watcher =
+            start_watcher(true, Config::default(), policy, &[], &loop_sender).unwrap();
+        watcher.native_watches_left = Some(2);
+
+        watcher
+            .watch_degraded(&root, RecursiveMode::Recursive)
+            .unwrap();
+
+        assert!(watcher.is_degraded());
+        let degraded = &watcher.degraded[&root];
+        assert_eq!(degraded.native_dirs, [root.clone(), root.join("a")]);
+        assert_eq!(watcher.polled_paths(), [root.join("a/b")]);
+        assert_eq!(
+            watcher.poll_config.poll_interval(),
+            Some(Duration::from_millis(50))
+        );
+
+        watcher.unwatch(&root).unwrap();
+        assert!(!watcher.is_degraded());
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::{
+    Backend, DebounceMode, FileWatcher, FileWatcherError, RecursiveMode, ReportedEvents,
+    WatchLimitPolicy, WatchOptions, WatchRoot,
+};
+use std::path::Path;
+use std::time::Duration;
+
+/// Collects the options for a [`FileWatcher`] before it starts watching.
+#[derive(Debug, Default, Clone)]
+pub struct FileWatcherBuilder {
+    roots: Vec<WatchRoot>,
+    options: WatchOptions,
+}
+
+impl FileWatcherBuilder {
+    /// Starts with `watch_path` as the only, recursively watched, root.
+    #[must_use]
+    pub fn new(watch_path: &Path) -> Self {
+        Self::default().root(watch_path, RecursiveMode::Recursive)
+    }
+
+    /// Adds a root to watch. Giving a path that is already a root replaces its recursive mode.
+    #[must_use]
+    pub fn root(self, path: &Path, recursive_mode: RecursiveMode) -> Self {
+        self.watch_root(WatchRoot::new(path, recursive_mode))
+    }
+
+    /// Like [`Self::root`], for roots with a depth limit.
+    #[must_use]
+    pub fn watch_root(mut self, root: WatchRoot) -> Self {
+        self.roots.retain(|existing| existing.path != root.path);
+        self.roots.push(root);
+        self
+    }
+
+    #[must_use]
+    pub const fn events(mut self, events: ReportedEvents) -> Self {
+        self.options.events = events;
+        self
+    }
+
+    #[must_use]
+    pub const fn debounce(mut self, window: Duration) -> Self {
+        self.options.debounce = window;
+        self
+    }
+
+    #[must_use]
+    pub const fn debounce_mode(mut self, mode: DebounceMode) -> Self {
+        self.options.debounce_mode = mode;
+        self
+    }
+
+    /// Only report paths matching `pattern`, relative to the watch root. May be given several times.
+    #[must_use]
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.options.include.push(pattern.to_string());
+        self
+    }
+
+    /// Never report paths matching `pattern`, relative to the watch root. Takes precedence over includes.
+    #[must_use]
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.options.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Adds an include glob, or an exclude glob if `pattern` starts with `!`.
+    #[must_use]
+    pub fn glob(self, pattern: &str) -> Self {
+        match pattern.strip_prefix('!') {
+            Some(excluded) => self.exclude(excluded),
+            None => self.include(pattern),
+        }
+    }
+
+    /// Suppress changes to paths that the ignore files in the watched tree ignore.
+    /// The rules are reloaded whenever one of the ignore files changes.
+    #[must_use]
+    pub const fn respect_ignore_files(mut self, respect: bool) -> Self {
+        self.options.respect_ignore_files = respect;
+        self
+    }
+
+    /// Selects between native events and polling. See [`Backend`].
+    #[must_use]
+    pub const fn backend(mut self, backend: Backend) -> Self {
+        self.options.backend = backend;
+        self
+    }
+
+    /// What to do when the system runs out of native watches. See [`WatchLimitPolicy`].
+    #[must_use]
+    pub const fn watch_limit_policy(mut self, policy: WatchLimitPolicy) -> Self {
+        self.options.watch_limit_policy = policy;
+        self
+    }
+
+    /// Only report a modification if the contents of the file changed. Files up to
+    /// `max_size` bytes are hashed, larger files are compared by size and modification time.
+    #[must_use]
+    pub const fn hash_contents(mut self, max_size: u64) -> Self {
+        self.options.hash_contents_up_to = Some(max_size);
+        self
+    }
+
+    /// Keep a manifest of the watched trees in `path`, and report what changed since it was
+    /// written when the watcher starts. The file is written when the watcher is dropped.
+    #[must_use]
+    pub fn state_file(mut self, path: &Path) -> Self {
+        self.options.state_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Also write the state file every `interval`, so less is reported twice after a crash.
+    /// Each save walks the whole tree on a background thread.
+    #[must_use]
+    pub const fn save_state_every(mut self, interval: Duration) -> Self {
+        self.options.save_state_every = Some(interval);
+        self
+    }
+
+    /// Whether to report atomic saves by editors as one modification of the saved file. Files
+    /// with names like editor temporaries, such as `notes~` or `4913`, are then not reported.
+    #[must_use]
+    pub const fn detect_atomic_saves(mut self, detect: bool) -> Self {
+        self.options.detect_atomic_saves = detect;
+        self
+    }
+
+    /// Find the changes missed when the backend loses events, by comparing against a snapshot of
+    /// the tree kept in memory. Without it, lost events are reported as [`crate::ChangeMessage::RescanRequired`].
+    #[must_use]
+    pub const fn rescan_on_overflow(mut self, rescan: bool) -> Self {
+        self.options.rescan_on_overflow = rescan;
+        self
+    }
+
+    /// Watch a root again when it is created again after being removed, as with `rm -rf build && mkdir build`.
+    #[must_use]
+    pub const fn rewatch_removed_roots(mut self, rewatch: bool) -> Self {
+        self.options.rewatch_removed_roots = rewatch;
+        self
+    }
+
+    /// Accept paths that do not exist yet, and start watching them once they are created.
+    #[must_use]
+    pub const fn wait_for_missing_roots(mut self, wait: bool) -> Self {
+        self.options.wait_for_missing_roots = wait;
+        self
+    }
+
+    /// # Errors
+    ///
+    pub fn build(self) -> Result<FileWatcher, FileWatcherError> {
+        FileWatcher::with_roots(&self.roots, &self.options)
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::ChangeMessage;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The effect a batch has had on a single path so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NetChange {
+    Created,
+    Modified,
+    Removed,
+    /// The path was renamed from another path, which has not been touched otherwise.
+    RenamedFrom(PathBuf),
+    RescanRequired,
+    RootRemoved,
+}
+
+/// Reduces the changes of a batch to one change per path, with the net effect of all of them.
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    /// The sequence number keeps the changes in the order their paths were first touched.
+    paths: HashMap<PathBuf, (u64, NetChange)>,
+    /// Outlives a path's entry, so a path that is touched again keeps its place.
+    first_touched: HashMap<PathBuf, u64>,
+    next_sequence: u64,
+}
+
+impl Coalescer {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, message: ChangeMessage) {
+        match message {
+            ChangeMessage::Created(path) => self.created(path),
+            ChangeMessage::Modified(path) => self.modified(path),
+            ChangeMessage::Removed(path) => self.removed(path),
+            ChangeMessage::Renamed { from, to } => self.renamed(from, to),
+            ChangeMessage::RescanRequired(root) => {
+                self.take(&root);
+                self.set(root, NetChange::RescanRequired);
+            }
+            ChangeMessage::RootRemoved(root) => {
+                self.take(&root);
+                self.set(root, NetChange::RootRemoved);
+            }
+        }
+    }
+
+    fn created(&mut self, path: PathBuf) {
+        let net = match self.take(&path) {
+            // replaced within the batch
+            Some(NetChange::Removed) => NetChange::Modified,
+            Some(net) => net,
+            None => NetChange::Created,
+        };
+        self.set(path, net);
+    }
+
+    fn modified(&mut self, path: PathBuf) {
+        let net = match self.take(&path) {
+            Some(NetChange::RenamedFrom(from)) => {
+                // no longer the same file as before, so report both sides on their own
+                self.removed(from);
+                NetChange::Created
+            }
+            Some(net) => net,
+            None => NetChange::Modified,
+        };
+        self.set(path, net);
+    }
+
+    fn removed(&mut self, path: PathBuf) {
+        match self.take(&path) {
+            Some(NetChange::Created) => {}
+            Some(NetChange::RenamedFrom(from)) => self.removed(from),
+            _ => self.set(path, NetChange::Removed),
+        }
+    }
+
+    fn renamed(&mut self, from: PathBuf, to: PathBuf) {
+        match self.take(&from) {
+            None => {
+                self.take(&to);
+                self.set(to, NetChange::RenamedFrom(from));
+            }
+            // renamed twice, or renamed back
+            Some(NetChange::RenamedFrom(original)) => {
+                self.take(&to);
+                if original != to {
+                    self.set(to, NetChange::RenamedFrom(original));
+                }
+            }
+            Some(NetChange::Created) => self.created(to),
+            Some(NetChange::Modified | NetChange::Removed) => {
+                self.set(from, NetChange::Removed);
+                self.created(to);
+            }
+            Some(net @ (NetChange::RescanRequired | NetChange::RootRemoved)) => {
+                self.set(from, net);
+                self.created(to);
+            }
+        }
+    }
+
+    fn take(&mut self, path: &PathBuf) -> Option<NetChange> {
+        self.paths.remove(path).map(|(_, net)| net)
+    }
+
+    fn set(&mut self, path: PathBuf, net: NetChange) {
+        let sequence = match self.first_touched.get(&path) {
+            Some(sequence) => *sequence,
+            None => {
+                let sequence = self.next_sequence;
+                self.next_sequence += 1;
+                self.first_touched.insert(path.clone(), sequence);
+                sequence
+            }
+        };
+        self.paths.insert(path, (sequence, net));
+    }
+
+    /// The net changes of the batch, which starts over empty.
+    pub(crate) fn flush(&mut self) -> Vec<ChangeMessage> {
+        self.first_touched.clear();
+        let mut paths: Vec<_> = self.paths.drain().collect();
+        paths.sort_by_key(|(_, (sequence, _))| *sequence);
+        paths
+            .into_iter()
+            .map(|(path, (_, net))| match net {
+                NetChange::Created => ChangeMessage::Created(path),
+                NetChange::Modified => ChangeMessage::Modified(path),
+                NetChange::Removed => ChangeMessage::Removed(path),
+                NetChange::RenamedFrom(from) => ChangeMessage::Renamed { from, to: path },
+                NetChange::RescanRequired => ChangeMessage::RescanRequired(path),
+                NetChange::RootRemoved => ChangeMessage::RootRemoved(path),
+            })
+            .collect()
+    }
+}
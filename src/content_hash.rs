@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::ChangeMessage;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// 64-bit FNV-1a. Unlike the std hashers its output is fixed, so hashes written to the state
+/// file stay comparable across toolchain upgrades.
+#[derive(Debug)]
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// What a file looked like the last time it was reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Fingerprint {
+    /// Hash of the contents, for files at or below the size limit.
+    Contents(u64),
+    /// Size and modification time, for files above the size limit.
+    Metadata { size: u64, modified: SystemTime },
+}
+
+impl Fingerprint {
+    /// Hashes the contents if the file is at most `max_hash_size` bytes, otherwise uses size and modification time.
+    ///
+    /// # Errors
+    ///
+    pub(crate) fn of(path: &Path, max_hash_size: u64) -> io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        if metadata.len() > max_hash_size {
+            return Ok(Self::Metadata {
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+
+        let mut file = File::open(path)?;
+        let mut hasher = Fnv1a::default();
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.write(&buffer[..read]);
+        }
+
+        Ok(Self::Contents(hasher.finish()))
+    }
+}
+
+/// How long a file has to be left alone before its contents are compared. Saves are often
+/// written in steps, like a truncate followed by the write, and must not be hashed halfway.
+const SETTLE_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+struct Settling {
+    last_change: Instant,
+    /// Report the file as modified if the contents differ. Otherwise only its fingerprint is taken.
+    report: bool,
+}
+
+/// Drops modifications that did not change the contents of a file.
+///
+/// Modifications are held until the file has settled, and then compared against the contents
+/// as they were last reported. Fingerprints are taken lazily, the first time a path shows up
+/// in a change, so the first modification of a file is always reported.
+#[derive(Debug)]
+pub(crate) struct ContentFilter {
+    max_hash_size: u64,
+    /// Fingerprints of the contents as they were when last reported.
+    fingerprints: HashMap<PathBuf, Fingerprint>,
+    settling: HashMap<PathBuf, Settling>,
+}
+
+impl ContentFilter {
+    pub(crate) fn new(max_hash_size: u64) -> Self {
+        Self {
+            max_hash_size,
+            fingerprints: HashMap::new(),
+            settling: HashMap::new(),
+        }
+    }
+
+    /// Returns `message` if it is delivered right away. Modifications are held until the file
+    /// has settled, see [`Self::expire`].
+    pub(crate) fn push(&mut self, message: ChangeMessage, now: Instant) -> Option<ChangeMessage> {
+        match &message {
+            ChangeMessage::Modified(path) => {
+                self.settling
+                    .entry(path.clone())
+                    .and_modify(|settling| settling.last_change = now)
+                    .or_insert(Settling {
+                        last_change: now,
+                        report: true,
+                    });
+                return None;
+            }
+            ChangeMessage::Created(path) => {
+                // the writes that fill a new file are part of its creation
+                self.fingerprints.remove(path);
+                self.settling.insert(
+                    path.clone(),
+                    Settling {
+                        last_change: now,
+                        report: false,
+                    },
+                );
+            }
+            ChangeMessage::Removed(path) => {
+                self.fingerprints.remove(path);
+                self.settling.remove(path);
+            }
+            ChangeMessage::Renamed { from, to } => {
+                // the contents move along with the name
+                if let Some(fingerprint) = self.fingerprints.remove(from) {
+                    self.fingerprints.insert(to.clone(), fingerprint);
+                }
+                if let Some(settling) = self.settling.remove(from) {
+                    self.settling.insert(to.clone(), settling);
+                }
+            }
+            ChangeMessage::RescanRequired(root) | ChangeMessage::RootRemoved(root) => {
+                // missed changes would otherwise be compared against stale fingerprints
+                self.fingerprints.retain(|path, _| !path.starts_with(root));
+                self.settling.retain(|path, _| !path.starts_with(root));
+            }
+        }
+        Some(message)
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.settling
+            .values()
+            .map(|settling| settling.last_change + SETTLE_WINDOW)
+            .min()
+    }
+
+    /// Compares the files that have settled, and returns the ones whose contents changed.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<ChangeMessage> {
+        let mut settled: Vec<(PathBuf, Settling)> = Vec::new();
+        self.settling.retain(|path, settling| {
+            if settling.last_change + SETTLE_WINDOW > now {
+                return true;
+            }
+            settled.push((path.clone(), *settling));
+            false
+        });
+        settled.sort_by_key(|(_, settling)| settling.last_change);
+
+        settled
+            .into_iter()
+            .filter(|(path, settling)| self.refresh(path) && settling.report)
+            .map(|(path, _)| ChangeMessage::Modified(path))
+            .collect()
+    }
+
+    /// Takes a new fingerprint of `path` and returns true if it differs from the previous one.
+    fn refresh(&mut self, path: &Path) -> bool {
+        let Ok(fingerprint) = Fingerprint::of(path, self.max_hash_size) else {
+            // directories, and files that are gone or unreadable, are always reported
+            self.fingerprints.remove(path);
+            return true;
+        };
+        self.fingerprints.insert(path.to_path_buf(), fingerprint) != Some(fingerprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hash_is_fnv1a() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+
+        assert_eq!(
+            Fingerprint::of(&file, 1).unwrap(),
+            Fingerprint::Contents(0xaf63_dc4c_8601_ec8c)
+        );
+    }
+}
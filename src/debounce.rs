@@ -0,0 +1,112 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::ChangeMessage;
+use crate::coalesce::Coalescer;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Decides at which edge of a burst of events the changes are delivered.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DebounceMode {
+    /// Deliver the first event for a path at once and drop repeats within the window.
+    #[default]
+    Leading,
+    /// Deliver once, after no events have arrived for the whole window.
+    Trailing,
+    /// Deliver the first event at once, and the repeats once the tree has been quiet for the window.
+    LeadingAndTrailing,
+    /// Collect changes until the tree has been quiet for the window, and deliver them as one batch
+    /// with one change per path. See [`crate::FileWatcher::take_batch`]. The receivers returned by
+    /// the `start_watch` functions get the changes of each batch one at a time.
+    Batch,
+}
+
+#[derive(Debug)]
+pub(crate) struct Debouncer {
+    mode: DebounceMode,
+    window: Duration,
+    last_sent: HashMap<ChangeMessage, Instant>,
+    pending: Vec<ChangeMessage>,
+    /// The same changes as `pending`, so a burst is not checked against it one by one.
+    pending_set: HashSet<ChangeMessage>,
+    last_event: Option<Instant>,
+    batch: Coalescer,
+}
+
+impl Debouncer {
+    pub(crate) fn new(mode: DebounceMode, window: Duration) -> Self {
+        Self {
+            mode,
+            window,
+            last_sent: HashMap::new(),
+            pending: Vec::new(),
+            pending_set: HashSet::new(),
+            last_event: None,
+            batch: Coalescer::default(),
+        }
+    }
+
+    /// Registers a change and returns it if it should be delivered right away.
+    pub(crate) fn push(&mut self, message: ChangeMessage, now: Instant) -> Option<ChangeMessage> {
+        self.last_event = Some(now);
+        let window = self.window;
+        self.last_sent
+            .retain(|_, sent| now.duration_since(*sent) < window);
+
+        let seen_recently = self.last_sent.contains_key(&message);
+        match self.mode {
+            DebounceMode::Leading => {
+                if seen_recently {
+                    return None;
+                }
+                self.last_sent.insert(message.clone(), now);
+                Some(message)
+            }
+            DebounceMode::Trailing => {
+                self.add_pending(message);
+                None
+            }
+            DebounceMode::LeadingAndTrailing => {
+                if seen_recently {
+                    self.add_pending(message);
+                    return None;
+                }
+                self.last_sent.insert(message.clone(), now);
+                Some(message)
+            }
+            DebounceMode::Batch => {
+                self.batch.push(message);
+                None
+            }
+        }
+    }
+
+    fn add_pending(&mut self, message: ChangeMessage) {
+        if self.pending_set.insert(message.clone()) {
+            self.pending.push(message);
+        }
+    }
+
+    /// The point in time where the pending changes should be flushed, if there are any.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        if self.pending.is_empty() && self.batch.is_empty() {
+            return None;
+        }
+        self.last_event
+            .and_then(|last| last.checked_add(self.window))
+    }
+
+    pub(crate) fn flush(&mut self) -> Vec<ChangeMessage> {
+        if self.mode == DebounceMode::Batch {
+            return self.batch.flush();
+        }
+        self.pending_set.clear();
+        std::mem::take(&mut self.pending)
+    }
+
+    pub(crate) fn is_batching(&self) -> bool {
+        self.mode == DebounceMode::Batch
+    }
+}
@@ -0,0 +1,665 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::atomic_save::AtomicSaveDetector;
+use crate::content_hash::ContentFilter;
+use crate::debounce::Debouncer;
+use crate::filter::PathFilter;
+use crate::handlers::Dispatch;
+use crate::ignore_rules::IgnoreRules;
+use crate::missing_roots::MissingRoots;
+use crate::notifier::Notifier;
+use crate::rename::RenamePairer;
+use crate::rescan::Rescanner;
+use crate::roots::{WatchRoot, directories_within, root_for};
+use crate::snapshot::Snapshot;
+use crate::state::PeriodicSave;
+use crate::{
+    BackendWatcher, ChangeMessage, FileWatcherError, ReportedEvents,
+    map_notify_error_to_file_watcher_error, update_backend_watches,
+};
+use message_channel::Sender;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Result as NotifyResult};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use tracing::{debug, error, warn};
+
+/// What the event loop receives, from the backend or from the owning [`crate::FileWatcher`].
+#[derive(Debug)]
+pub(crate) enum LoopInput {
+    Event(NotifyResult<Event>),
+    RootsChanged(Vec<WatchRoot>),
+    /// Handlers have been registered, so changes should be forwarded to their dispatch thread too.
+    StartDispatch(mpsc::Sender<Dispatch>),
+    /// Changes found without events, for example since the saved state. Acknowledged once delivered.
+    Replay(Vec<ChangeMessage>, mpsc::Sender<()>),
+}
+
+/// Receives raw events from the backend on its own thread, and delivers them to the
+/// receiver according to the watch options. Runs until the watcher is dropped.
+#[derive(Debug)]
+pub(crate) struct EventLoop {
+    pub(crate) sender: Sender<ChangeMessage>,
+    pub(crate) batch_sender: Sender<Vec<ChangeMessage>>,
+    /// Send batches to `sender` one change at a time, for callers that only have that receiver.
+    pub(crate) flatten_batches: bool,
+    pub(crate) error_sender: Sender<FileWatcherError>,
+    pub(crate) notifier: Arc<Notifier>,
+    pub(crate) events: ReportedEvents,
+    pub(crate) filter: PathFilter,
+    pub(crate) ignore_rules: Option<IgnoreRules>,
+    pub(crate) content_filter: Option<ContentFilter>,
+    pub(crate) debouncer: Debouncer,
+    pub(crate) renames: RenamePairer,
+    pub(crate) atomic_saves: Option<AtomicSaveDetector>,
+    pub(crate) rescanner: Option<Rescanner>,
+    pub(crate) rewatch_removed_roots: bool,
+    /// Roots whose directory or file does not exist, and which have no backend watch until it does.
+    pub(crate) missing_roots: MissingRoots,
+    pub(crate) wait_for_missing_roots: bool,
+    pub(crate) roots: Vec<WatchRoot>,
+    /// Weak, since the watcher owns the sending side of the channel this loop runs on.
+    pub(crate) watcher: Weak<Mutex<BackendWatcher>>,
+    pub(crate) dispatch: Option<mpsc::Sender<Dispatch>>,
+    pub(crate) periodic_save: Option<PeriodicSave>,
+}
+
+impl EventLoop {
+    pub(crate) fn run(mut self, event_receiver: &mpsc::Receiver<LoopInput>) {
+        loop {
+            let deadline = self
+                .debouncer
+                .deadline()
+                .into_iter()
+                .chain(self.renames.deadline())
+                .chain(
+                    self.atomic_saves
+                        .as_ref()
+                        .and_then(AtomicSaveDetector::deadline),
+                )
+                .chain(
+                    self.content_filter
+                        .as_ref()
+                        .and_then(ContentFilter::deadline),
+                )
+                .chain(self.periodic_save.as_ref().and_then(|save| save.next))
+                .min();
+            let received = match deadline {
+                Some(deadline) => {
+                    event_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => event_receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                Ok(LoopInput::Event(Ok(event))) => self.handle_event(&event),
+                Ok(LoopInput::RootsChanged(roots)) => {
+                    if let Some(ignore_rules) = &mut self.ignore_rules {
+                        *ignore_rules = IgnoreRules::load(&roots);
+                    }
+                    self.missing_roots.retain(&roots);
+                    if self.wait_for_missing_roots {
+                        for root in roots.iter().filter(|root| !root.path.exists()) {
+                            self.missing_roots.add(root.clone(), true);
+                        }
+                    }
+                    self.roots = roots;
+                    let active_roots = self.active_roots();
+                    if let Some(rescanner) = &mut self.rescanner {
+                        rescanner.set_roots(&active_roots);
+                    }
+                    self.advance_missing_roots();
+                }
+                Ok(LoopInput::StartDispatch(dispatch)) => self.dispatch = Some(dispatch),
+                Ok(LoopInput::Replay(messages, done)) => {
+                    self.replay(messages);
+                    let _ = done.send(());
+                }
+                Ok(LoopInput::Event(Err(e))) => self.handle_error(e),
+                Err(RecvTimeoutError::Timeout) => self.handle_timeout(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn handle_error(&self, e: notify::Error) {
+        error!(
+            error = ?e,
+            roots = ?self.roots,
+            "FileWatcher internal background watch error"
+        );
+        // errors without a path concern the watch as a whole
+        let path = e
+            .paths
+            .first()
+            .cloned()
+            .or_else(|| self.roots.first().map(|root| root.path.clone()))
+            .unwrap_or_default();
+        if let Err(e) = self
+            .error_sender
+            .send(map_notify_error_to_file_watcher_error(e, &path))
+        {
+            error!(error = ?e, "FileWatcher internal channel send error: receiver likely dropped");
+        }
+    }
+
+    fn handle_timeout(&mut self) {
+        let now = Instant::now();
+        let moved_out = self.renames.expire(now);
+        let moved_out = self.detect_atomic_saves(moved_out, now);
+        self.process(moved_out, now);
+        if let Some(atomic_saves) = &mut self.atomic_saves {
+            let removed = atomic_saves.expire(now);
+            self.process(removed, now);
+        }
+        if let Some(content_filter) = &mut self.content_filter {
+            for message in content_filter.expire(now) {
+                self.debounce(message, now);
+            }
+        }
+        if self
+            .debouncer
+            .deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            let messages = self.debouncer.flush();
+            if self.debouncer.is_batching() {
+                self.send_batch(messages);
+            } else {
+                for message in messages {
+                    self.send(message);
+                }
+            }
+        }
+        if let Some(periodic_save) = &mut self.periodic_save {
+            periodic_save.save_if_due(&self.roots, now);
+        }
+    }
+
+    /// Stops the backend watch of roots that were removed or moved away, and reports them as
+    /// removed. Returns the paths of those roots.
+    fn check_removed_roots(&mut self, event: &Event) -> Vec<PathBuf> {
+        // the root may already have been created again, so this can not wait for it to be gone
+        let removes_root = match event.kind {
+            EventKind::Remove(_) => true,
+            // the root itself was moved away
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => event.tracker().is_none(),
+            EventKind::Modify(ModifyKind::Name(_)) => false,
+            _ => return Vec::new(),
+        };
+        let removed: Vec<WatchRoot> = self
+            .roots
+            .iter()
+            .filter(|root| {
+                event.paths.contains(&root.path)
+                    && (removes_root || !root.path.exists())
+                    && !self.missing_roots.contains(root)
+            })
+            .cloned()
+            .collect();
+        if removed.is_empty() {
+            return Vec::new();
+        }
+
+        let before = self.active_roots();
+        for root in &removed {
+            self.missing_roots
+                .add(root.clone(), self.rewatch_removed_roots);
+        }
+        self.update_watches(&before);
+        let active_roots = self.active_roots();
+        if let Some(rescanner) = &mut self.rescanner {
+            rescanner.set_roots(&active_roots);
+        }
+
+        removed
+            .into_iter()
+            .map(|root| {
+                warn!(root = ?root.path, "Watch root was removed");
+                self.send_unfiltered(ChangeMessage::RootRemoved(root.path.clone()));
+                root.path
+            })
+            .collect()
+    }
+
+    /// Follows the creation of the path components of missing roots. Roots that exist again are
+    /// watched, and what is in them is reported as created. Returns the paths of those roots.
+    pub(crate) fn advance_missing_roots(&mut self) -> Vec<PathBuf> {
+        let before = self.active_roots();
+        let appeared = {
+            let Some(watcher) = self.watcher.upgrade() else {
+                return Vec::new();
+            };
+            let Ok(mut watcher) = watcher.lock() else {
+                return Vec::new();
+            };
+            self.missing_roots.advance(&mut watcher, &before)
+        };
+        if appeared.is_empty() {
+            return Vec::new();
+        }
+
+        self.update_watches(&before);
+        let active_roots = self.active_roots();
+        if let Some(rescanner) = &mut self.rescanner {
+            rescanner.set_roots(&active_roots);
+        }
+
+        let now = Instant::now();
+        let appeared_paths = appeared.iter().map(|root| root.path.clone()).collect();
+        for root in appeared {
+            debug!(root = ?root.path, "Watch root exists, watching it");
+            // whatever was created before the watch was in place
+            let mut created = vec![ChangeMessage::Created(root.path.clone())];
+            if let Ok(snapshot) = Snapshot::capture_root(&root, None) {
+                created.extend(
+                    snapshot
+                        .entries()
+                        .keys()
+                        .map(|relative| ChangeMessage::Created(root.path.join(relative))),
+                );
+            }
+            self.process(created, now);
+        }
+        appeared_paths
+    }
+
+    /// Returns true if `message` is the creation of a root, as seen by the watch of an ancestor
+    /// after an earlier event already found the root and reported it as created.
+    fn is_late_root_creation(&self, message: &ChangeMessage) -> bool {
+        let ChangeMessage::Created(path) = message else {
+            return false;
+        };
+        let active_roots = self.active_roots();
+        let seen_by_root = path.parent().is_some_and(|parent| {
+            active_roots
+                .iter()
+                .any(|root| root.watches_directory(parent))
+        });
+        !seen_by_root && active_roots.iter().any(|root| root.path == *path)
+    }
+
+    fn active_roots(&self) -> Vec<WatchRoot> {
+        self.roots
+            .iter()
+            .filter(|root| !self.missing_roots.contains(root))
+            .cloned()
+            .collect()
+    }
+
+    /// Moves the backend watches from the roots that were active `before` to the ones active now.
+    fn update_watches(&self, before: &[WatchRoot]) {
+        let Some(watcher) = self.watcher.upgrade() else {
+            return;
+        };
+        let Ok(mut watcher) = watcher.lock() else {
+            return;
+        };
+        if let Err(e) = update_backend_watches(&mut watcher, before, &self.active_roots()) {
+            warn!(error = ?e, "Failed to update watches of missing roots");
+        }
+    }
+
+    /// Delivers changes that did not come from the backend. They are filtered, but not debounced.
+    fn replay(&self, messages: Vec<ChangeMessage>) {
+        let messages: Vec<_> = messages
+            .into_iter()
+            .filter(|message| self.wants(message) && self.is_reported(message))
+            .collect();
+        if self.debouncer.is_batching() {
+            self.send_batch(messages);
+        } else {
+            for message in messages {
+                self.send(message);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: &Event) {
+        if event.need_rescan() {
+            self.handle_rescan(event);
+            return;
+        }
+        if let Some(rescanner) = &mut self.rescanner {
+            rescanner.observe(event);
+        }
+        let removed_roots = self.check_removed_roots(event);
+        let appeared_roots = if matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) && event
+            .paths
+            .iter()
+            .any(|path| self.missing_roots.is_on_the_way(path))
+        {
+            self.advance_missing_roots()
+        } else {
+            Vec::new()
+        };
+        if !removed_roots.is_empty() && event.paths.iter().all(|path| removed_roots.contains(path))
+        {
+            // already reported as RootRemoved
+            return;
+        }
+
+        if let Some(ignore_rules) = &mut self.ignore_rules
+            && matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            )
+            && event
+                .paths
+                .iter()
+                .any(|path| IgnoreRules::is_ignore_file(path))
+        {
+            ignore_rules.reload();
+        }
+
+        if matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        ) {
+            for path in &event.paths {
+                self.watch_new_directory(path);
+            }
+        }
+
+        // atomic saves are made of creations, removals and renames, even when only modifications are reported
+        let accepted = if self.atomic_saves.is_some() {
+            ReportedEvents::All.accepts(&event.kind)
+        } else {
+            self.events.accepts(&event.kind)
+        };
+        if !accepted {
+            // ignore metadata, attrib, open, etc.
+            return;
+        }
+
+        let now = Instant::now();
+        let mut messages = match event.kind {
+            EventKind::Modify(ModifyKind::Name(mode)) => self.renames.push(mode, event, now),
+            _ => ChangeMessage::from_event(event),
+        };
+        messages.dedup();
+        // already reported as created, along with what was in them
+        messages.retain(|message| !appeared_roots.iter().any(|root| root == message.path()));
+        messages.retain(|message| !self.is_late_root_creation(message));
+        let messages = self.detect_atomic_saves(messages, now);
+        self.process(messages, now);
+    }
+
+    /// The backend dropped events. Finds the missed changes if there is a snapshot to compare
+    /// against, and tells the receiver to rescan the affected roots otherwise.
+    fn handle_rescan(&mut self, event: &Event) {
+        warn!(paths = ?event.paths, "FileWatcher backend lost events");
+        let roots: Vec<WatchRoot> = self
+            .roots
+            .iter()
+            .filter(|root| {
+                event.paths.is_empty()
+                    || event
+                        .paths
+                        .iter()
+                        .any(|path| path.starts_with(&root.path) || root.path.starts_with(path))
+            })
+            .cloned()
+            .collect();
+
+        let mut missed = Vec::new();
+        for root in &roots {
+            match self
+                .rescanner
+                .as_mut()
+                .and_then(|rescanner| rescanner.rescan(root))
+            {
+                Some(changes) => missed.extend(changes),
+                None => self.send_unfiltered(ChangeMessage::RescanRequired(root.path.clone())),
+            }
+        }
+
+        // overlapping roots find the same changes
+        let mut seen = HashSet::new();
+        missed.retain(|change| seen.insert(change.clone()));
+        self.process(missed, Instant::now());
+    }
+
+    /// For changes that concern a root as a whole, which the path filters do not apply to.
+    fn send_unfiltered(&self, message: ChangeMessage) {
+        if self.debouncer.is_batching() {
+            self.send_batch(vec![message]);
+        } else {
+            self.send(message);
+        }
+    }
+
+    fn detect_atomic_saves(
+        &mut self,
+        messages: Vec<ChangeMessage>,
+        now: Instant,
+    ) -> Vec<ChangeMessage> {
+        match &mut self.atomic_saves {
+            Some(atomic_saves) => atomic_saves.push(messages, now),
+            None => messages,
+        }
+    }
+
+    /// Filters and debounces changes on their way to the receiver.
+    fn process(&mut self, messages: Vec<ChangeMessage>, now: Instant) {
+        for message in messages {
+            if !self.wants(&message) || !self.is_reported(&message) {
+                continue;
+            }
+            let message = match &mut self.content_filter {
+                Some(content_filter) => match content_filter.push(message, now) {
+                    Some(message) => message,
+                    None => continue,
+                },
+                None => message,
+            };
+            self.debounce(message, now);
+        }
+    }
+
+    fn debounce(&mut self, message: ChangeMessage, now: Instant) {
+        if let Some(message) = self.debouncer.push(message, now) {
+            self.send(message);
+        }
+    }
+
+    /// Depth limited and degraded roots watch each directory on its own, so new directories need new watches.
+    fn watch_new_directory(&self, path: &Path) {
+        let Some(watcher) = self.watcher.upgrade() else {
+            return;
+        };
+        let Ok(mut watcher) = watcher.lock() else {
+            return;
+        };
+        if root_for(&self.roots, path).is_none() {
+            // inside the ancestor of a missing root
+            return;
+        }
+        let depth_limited_root = self
+            .roots
+            .iter()
+            .find(|root| root.wants_directory_watch(path));
+        if depth_limited_root.is_none() && !watcher.is_degraded() {
+            return;
+        }
+        if !path.is_dir() {
+            return;
+        }
+
+        let Some(root) = depth_limited_root else {
+            watcher.cover_new_directory(path);
+            return;
+        };
+
+        let depth = path
+            .strip_prefix(&root.path)
+            .map_or(0, |relative| relative.components().count());
+        let remaining_depth = root.max_depth.unwrap_or_default().saturating_sub(depth);
+        // subdirectories may have been created before the watch was in place
+        for dir in directories_within(path, remaining_depth) {
+            if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                warn!(error = ?e, path = ?dir, "Failed to watch new directory");
+            }
+        }
+    }
+
+    fn wants(&self, message: &ChangeMessage) -> bool {
+        self.events == ReportedEvents::All || matches!(message, ChangeMessage::Modified(_))
+    }
+
+    fn is_reported(&self, message: &ChangeMessage) -> bool {
+        let Some(root) = root_for(&self.roots, message.path()) else {
+            return false;
+        };
+        if !self.filter.matches(message, &root.path) {
+            return false;
+        }
+
+        self.ignore_rules
+            .as_ref()
+            .is_none_or(|ignore_rules| match message {
+                ChangeMessage::Renamed { from, to } => {
+                    !ignore_rules.is_ignored(from) || !ignore_rules.is_ignored(to)
+                }
+                _ => !ignore_rules.is_ignored(message.path()),
+            })
+    }
+
+    fn send(&self, message: ChangeMessage) {
+        self.dispatch(&message);
+        if let Err(e) = self.sender.send(message) {
+            error!(
+                error = ?e,
+                "FileWatcher internal channel send error: receiver likely dropped"
+            );
+        }
+        self.notifier.notify();
+    }
+
+    fn send_batch(&self, batch: Vec<ChangeMessage>) {
+        if batch.is_empty() {
+            // everything in it cancelled out
+            return;
+        }
+        if self.flatten_batches {
+            for message in batch {
+                self.send(message);
+            }
+            return;
+        }
+        for message in &batch {
+            self.dispatch(message);
+        }
+        if let Err(e) = self.batch_sender.send(batch) {
+            error!(
+                error = ?e,
+                "FileWatcher internal channel send error: receiver likely dropped"
+            );
+        }
+        self.notifier.notify();
+    }
+
+    fn dispatch(&self, message: &ChangeMessage) {
+        if let Some(dispatch) = &self.dispatch {
+            let root = root_for(&self.roots, message.path())
+                .map(|root| root.path.clone())
+                .unwrap_or_default();
+            if dispatch.send((message.clone(), root)).is_err() {
+                error!("FileWatcher handler dispatch thread has stopped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, WatchOptions, spawn_watch};
+    use message_channel::Receiver;
+    use notify::event::Flag;
+    use std::time::Duration;
+
+    /// Only the injected events reach the loop, since the poll backend does not scan again within the test.
+    fn options(rescan_on_overflow: bool) -> WatchOptions {
+        WatchOptions {
+            events: ReportedEvents::All,
+            debounce: Duration::ZERO,
+            backend: Backend::Poll {
+                interval: Duration::from_secs(3600),
+                compare_contents: false,
+            },
+            rescan_on_overflow,
+            ..WatchOptions::default()
+        }
+    }
+
+    fn lost_events(root: &Path) -> LoopInput {
+        LoopInput::Event(Ok(Event::new(EventKind::Other)
+            .set_flag(Flag::Rescan)
+            .add_path(root.to_path_buf())))
+    }
+
+    fn wait_for_changes(receiver: &Receiver<ChangeMessage>) -> Vec<ChangeMessage> {
+        let start = Instant::now();
+        let mut changes = Vec::new();
+        while changes.is_empty() && start.elapsed() < Duration::from_secs(3) {
+            std::thread::sleep(Duration::from_millis(50));
+            while let Ok(change) = receiver.recv() {
+                changes.push(change);
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn lost_events_require_a_rescan_without_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let handles = spawn_watch(&[WatchRoot::recursive(&root)], &options(false), false).unwrap();
+
+        handles.loop_sender.send(lost_events(&root)).unwrap();
+
+        assert_eq!(
+            wait_for_changes(&handles.receiver),
+            [ChangeMessage::RescanRequired(root)]
+        );
+    }
+
+    #[test]
+    fn lost_events_are_found_against_the_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        std::fs::create_dir(root.join("a")).unwrap();
+        std::fs::write(root.join("a/x.txt"), "x").unwrap();
+        let handles = spawn_watch(&[WatchRoot::recursive(&root)], &options(true), false).unwrap();
+
+        // the directory moves with its contents, and only the directory has an event
+        std::fs::rename(root.join("a"), root.join("b")).unwrap();
+        let moved: [PathBuf; 2] = [root.join("a"), root.join("b")];
+        let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)));
+        let rename = moved.into_iter().fold(rename, Event::add_path);
+        handles
+            .loop_sender
+            .send(LoopInput::Event(Ok(rename)))
+            .unwrap();
+        assert!(!wait_for_changes(&handles.receiver).is_empty());
+
+        std::fs::write(root.join("missed.txt"), "no event").unwrap();
+        handles.loop_sender.send(lost_events(&root)).unwrap();
+
+        assert_eq!(
+            wait_for_changes(&handles.receiver),
+            [ChangeMessage::Created(root.join("missed.txt"))]
+        );
+    }
+}
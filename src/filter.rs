@@ -0,0 +1,68 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::{ChangeMessage, FileWatcherError};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+/// Include and exclude globs, matched against paths relative to the watch root.
+#[derive(Debug, Clone)]
+pub(crate) struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+fn compile_glob(pattern: &str) -> Result<Glob, FileWatcherError> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| FileWatcherError::InvalidGlob(e.to_string()))
+}
+
+fn compile_set(patterns: &[String]) -> Result<GlobSet, FileWatcherError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(compile_glob(pattern)?);
+    }
+    builder
+        .build()
+        .map_err(|e| FileWatcherError::InvalidGlob(e.to_string()))
+}
+
+impl PathFilter {
+    /// # Errors
+    ///
+    pub(crate) fn new(include: &[String], exclude: &[String]) -> Result<Self, FileWatcherError> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(compile_set(include)?)
+        };
+
+        Ok(Self {
+            include,
+            exclude: compile_set(exclude)?,
+        })
+    }
+
+    pub(crate) fn matches_path(&self, path: &Path, root: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative))
+    }
+
+    /// A rename passes if either side of it does.
+    pub(crate) fn matches(&self, message: &ChangeMessage, root: &Path) -> bool {
+        match message {
+            ChangeMessage::Renamed { from, to } => {
+                self.matches_path(from, root) || self.matches_path(to, root)
+            }
+            _ => self.matches_path(message.path(), root),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::filter::PathFilter;
+use crate::{ChangeMessage, FileWatcherError};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{debug, error};
+
+/// Identifies a handler registered with [`crate::FileWatcher::on_change`], so it can be removed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
+
+pub type ChangeHandler = Box<dyn FnMut(&ChangeMessage) + Send>;
+
+/// A change together with the root it was reported through, which scoped handlers match against.
+pub(crate) type Dispatch = (ChangeMessage, PathBuf);
+
+struct RegisteredHandler {
+    id: HandlerId,
+    scope: Option<PathFilter>,
+    handler: ChangeHandler,
+}
+
+impl fmt::Debug for RegisteredHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredHandler")
+            .field("id", &self.id)
+            .field("scope", &self.scope)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs the registered handlers on a thread of its own, so slow handlers
+/// do not hold up the event loop or the backend.
+#[derive(Debug)]
+pub(crate) struct Dispatcher {
+    handlers: Arc<Mutex<Vec<RegisteredHandler>>>,
+    next_id: u64,
+}
+
+impl Dispatcher {
+    /// Starts the dispatch thread. It runs until the returned sender is dropped.
+    ///
+    /// # Errors
+    ///
+    pub(crate) fn spawn() -> Result<(Self, mpsc::Sender<Dispatch>), FileWatcherError> {
+        let handlers: Arc<Mutex<Vec<RegisteredHandler>>> = Arc::default();
+        let (sender, receiver) = mpsc::channel::<Dispatch>();
+
+        let thread_handlers = Arc::clone(&handlers);
+        thread::Builder::new()
+            .name("fs-change-detector-handlers".to_string())
+            .spawn(move || {
+                while let Ok((message, root)) = receiver.recv() {
+                    let Ok(mut handlers) = thread_handlers.lock() else {
+                        error!("FileWatcher handler list is poisoned, stopping dispatch");
+                        break;
+                    };
+                    for registered in handlers.iter_mut().filter(|registered| {
+                        registered
+                            .scope
+                            .as_ref()
+                            .is_none_or(|scope| scope.matches(&message, &root))
+                    }) {
+                        (registered.handler)(&message);
+                    }
+                }
+                debug!("FileWatcher handler dispatch stopped");
+            })
+            .map_err(|e| FileWatcherError::IoError(e.to_string()))?;
+
+        Ok((
+            Self {
+                handlers,
+                next_id: 0,
+            },
+            sender,
+        ))
+    }
+
+    /// # Errors
+    ///
+    pub(crate) fn add(
+        &mut self,
+        scope: Option<PathFilter>,
+        handler: ChangeHandler,
+    ) -> Result<HandlerId, FileWatcherError> {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        self.handlers
+            .lock()
+            .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))?
+            .push(RegisteredHandler { id, scope, handler });
+
+        Ok(id)
+    }
+
+    pub(crate) fn remove(&self, id: HandlerId) -> bool {
+        let Ok(mut handlers) = self.handlers.lock() else {
+            return false;
+        };
+        let count_before = handlers.len();
+        handlers.retain(|registered| registered.id != id);
+        handlers.len() != count_before
+    }
+}
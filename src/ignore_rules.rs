@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::roots::WatchRoot;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder};
+use notify::RecursiveMode;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// The `.gitignore`, `.ignore` and `.git/info/exclude` rules found in a watched tree.
+#[derive(Debug)]
+pub(crate) struct IgnoreRules {
+    roots: Vec<WatchRoot>,
+    /// One matcher per directory that has ignore files, shallowest first.
+    matchers: BTreeMap<PathBuf, Gitignore>,
+}
+
+impl IgnoreRules {
+    pub(crate) fn load(roots: &[WatchRoot]) -> Self {
+        let mut rules = Self {
+            roots: roots.to_vec(),
+            matchers: BTreeMap::new(),
+        };
+        rules.reload();
+        rules
+    }
+
+    /// Reads all ignore files again. Directories that are ignored are not searched.
+    pub(crate) fn reload(&mut self) {
+        self.matchers.clear();
+
+        for root in &self.roots {
+            let max_depth = match root.recursive_mode {
+                RecursiveMode::Recursive => root.max_depth,
+                RecursiveMode::NonRecursive => Some(0),
+            };
+            let walker = WalkBuilder::new(&root.path)
+                .hidden(false)
+                .parents(false)
+                .require_git(false)
+                .max_depth(max_depth)
+                .filter_entry(|entry| entry.file_name() != ".git")
+                .build();
+
+            for entry in walker.flatten() {
+                if entry
+                    .file_type()
+                    .is_some_and(|file_type| file_type.is_dir())
+                    && let Some(matcher) = build_matcher(entry.path())
+                {
+                    self.matchers.insert(entry.path().to_path_buf(), matcher);
+                }
+            }
+        }
+
+        debug!(directories = self.matchers.len(), "loaded ignore rules");
+    }
+
+    /// Returns true if the path is one of the files the rules are read from.
+    pub(crate) fn is_ignore_file(path: &Path) -> bool {
+        if path.file_name().is_some_and(|name| {
+            IGNORE_FILE_NAMES
+                .iter()
+                .any(|ignore_name| name == *ignore_name)
+        }) {
+            return true;
+        }
+        path.ends_with(".git/info/exclude")
+    }
+
+    pub(crate) fn is_ignored(&self, path: &Path) -> bool {
+        if path
+            .components()
+            .any(|component| component.as_os_str() == OsStr::new(".git"))
+        {
+            return true;
+        }
+
+        let is_dir = path.is_dir();
+        let mut ignored = false;
+        // deeper directories override the decisions of their parents
+        for (dir, matcher) in &self.matchers {
+            let Ok(relative) = path.strip_prefix(dir) else {
+                continue;
+            };
+            match matcher.matched_path_or_any_parents(relative, is_dir) {
+                Match::Ignore(_) => ignored = true,
+                Match::Whitelist(_) => ignored = false,
+                Match::None => {}
+            }
+        }
+
+        ignored
+    }
+}
+
+/// Combines the ignore files of a single directory. Later files take precedence,
+/// so `.ignore` overrides `.gitignore`, which overrides `.git/info/exclude`.
+fn build_matcher(dir: &Path) -> Option<Gitignore> {
+    let candidates = [
+        dir.join(".git").join("info").join("exclude"),
+        dir.join(".gitignore"),
+        dir.join(".ignore"),
+    ];
+
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found_any = false;
+    for candidate in candidates.iter().filter(|candidate| candidate.is_file()) {
+        found_any = true;
+        if let Some(e) = builder.add(candidate) {
+            warn!(error = ?e, path = ?candidate, "could not read ignore file");
+        }
+    }
+    if !found_any {
+        return None;
+    }
+
+    builder
+        .build()
+        .inspect_err(|e| warn!(error = ?e, dir = ?dir, "could not build ignore rules"))
+        .ok()
+}
@@ -2,17 +2,240 @@
  * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
  * Licensed under the MIT License. See LICENSE in the project root for license information.
  */
+mod atomic_save;
+mod backend;
+mod builder;
+mod coalesce;
+mod content_hash;
+mod debounce;
+mod event_loop;
+mod filter;
+mod handlers;
+mod ignore_rules;
+mod missing_roots;
+mod notifier;
+mod rename;
+mod rescan;
+mod roots;
+mod snapshot;
+mod state;
+#[cfg(feature = "stream")]
+mod stream;
+
+use crate::atomic_save::AtomicSaveDetector;
+use crate::backend::start_backend;
+use crate::content_hash::ContentFilter;
+use crate::debounce::Debouncer;
+use crate::event_loop::{EventLoop, LoopInput};
+use crate::filter::PathFilter;
+use crate::handlers::Dispatcher;
+use crate::ignore_rules::IgnoreRules;
+use crate::missing_roots::MissingRoots;
+use crate::notifier::Notifier;
+use crate::rename::RenamePairer;
+use crate::rescan::Rescanner;
+use crate::state::{PeriodicSave, StateFile};
 use message_channel::{Channel, Receiver};
-use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::Event;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Config, EventKind};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
 use std::time::{Duration, Instant};
-use notify::event::ModifyKind;
 use thiserror::Error;
 use tracing::{debug, error};
-use notify::{Event, Result as NotifyResult};
-#[derive(Debug)]
+
+use crate::backend::BackendWatcher;
+pub use crate::backend::{Backend, WatchLimitPolicy};
+pub use crate::builder::FileWatcherBuilder;
+pub use crate::debounce::DebounceMode;
+pub use crate::handlers::{ChangeHandler, HandlerId};
+pub use crate::roots::WatchRoot;
+pub use crate::snapshot::{Snapshot, SnapshotDiff, SnapshotEntry};
+#[cfg(feature = "stream")]
+pub use crate::stream::ChangeStream;
+pub use notify::RecursiveMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+    Renamed,
+    RescanRequired,
+    RootRemoved,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ChangeMessage {
-    SomeKindOfChange,
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// The backend lost events for this root, so anything below it may have changed.
+    RescanRequired(PathBuf),
+    /// The watch root itself was removed or moved away. Nothing below it is watched until it is back,
+    /// see [`WatchOptions::rewatch_removed_roots`].
+    RootRemoved(PathBuf),
+}
+
+impl ChangeMessage {
+    #[must_use]
+    pub const fn kind(&self) -> ChangeKind {
+        match self {
+            Self::Created(_) => ChangeKind::Created,
+            Self::Modified(_) => ChangeKind::Modified,
+            Self::Removed(_) => ChangeKind::Removed,
+            Self::Renamed { .. } => ChangeKind::Renamed,
+            Self::RescanRequired(_) => ChangeKind::RescanRequired,
+            Self::RootRemoved(_) => ChangeKind::RootRemoved,
+        }
+    }
+
+    /// The path as it is after the change. For renames this is the destination.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Created(path)
+            | Self::Modified(path)
+            | Self::Removed(path)
+            | Self::RescanRequired(path)
+            | Self::RootRemoved(path) => path,
+            Self::Renamed { to, .. } => to,
+        }
+    }
+
+    /// Converts a `notify` event into zero or more change messages, one per affected path.
+    #[must_use]
+    pub fn from_event(event: &Event) -> Vec<Self> {
+        match event.kind {
+            EventKind::Create(_) => event.paths.iter().cloned().map(Self::Created).collect(),
+            EventKind::Remove(_) => event.paths.iter().cloned().map(Self::Removed).collect(),
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => {
+                event.paths.iter().cloned().map(Self::Modified).collect()
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                vec![Self::Renamed {
+                    from: event.paths[0].clone(),
+                    to: event.paths[1].clone(),
+                }]
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                event.paths.iter().cloned().map(Self::Removed).collect()
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                event.paths.iter().cloned().map(Self::Created).collect()
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => event
+                .paths
+                .iter()
+                .map(|path| {
+                    // backend could not tell which side of the rename this is
+                    if path.exists() {
+                        Self::Created(path.clone())
+                    } else {
+                        Self::Removed(path.clone())
+                    }
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Selects which kinds of filesystem events a watcher reports.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReportedEvents {
+    /// Only data modifications of existing files.
+    #[default]
+    Modifications,
+    /// Data modifications as well as creation, removal and renames.
+    All,
+}
+
+impl ReportedEvents {
+    #[must_use]
+    pub const fn accepts(self, kind: &EventKind) -> bool {
+        match self {
+            Self::Modifications => {
+                matches!(
+                    kind,
+                    EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+                )
+            }
+            Self::All => matches!(
+                kind,
+                EventKind::Create(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Modify(
+                        ModifyKind::Data(_) | ModifyKind::Any | ModifyKind::Name(_)
+                    )
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    pub events: ReportedEvents,
+    /// Length of the window in which bursts of events are merged.
+    pub debounce: Duration,
+    pub debounce_mode: DebounceMode,
+    /// Globs relative to the watch root. When not empty, only matching paths are reported.
+    pub include: Vec<String>,
+    /// Globs relative to the watch root. Matching paths are never reported.
+    pub exclude: Vec<String>,
+    /// Suppress paths ignored by `.gitignore`, `.ignore` and `.git/info/exclude` files in the tree.
+    pub respect_ignore_files: bool,
+    pub backend: Backend,
+    pub watch_limit_policy: WatchLimitPolicy,
+    /// When set, modifications are only reported if the contents changed. Files up to this
+    /// many bytes are hashed, larger files are compared by size and modification time.
+    /// Modifications are compared once the file has been left alone for 100 ms.
+    pub hash_contents_up_to: Option<u64>,
+    /// When set, a manifest of the watched trees is kept in this file. It is written when the
+    /// [`FileWatcher`] is dropped, and the next watcher started with it first reports what changed since.
+    pub state_file: Option<PathBuf>,
+    /// Also write the state file at this interval, so a crash loses less. Each save walks the
+    /// whole tree, and hashes files if [`Self::hash_contents_up_to`] is set, on a background thread.
+    pub save_state_every: Option<Duration>,
+    /// Report editors saving through a temporary file, or by deleting and recreating the file,
+    /// as one modification of the saved file. Changes to their temporary files are not reported.
+    pub detect_atomic_saves: bool,
+    /// When the backend loses events, find the missed changes by comparing the tree against a
+    /// snapshot kept in memory, instead of reporting [`ChangeMessage::RescanRequired`].
+    pub rescan_on_overflow: bool,
+    /// Watch a removed root again once it exists again, and report what is in it as created.
+    pub rewatch_removed_roots: bool,
+    /// Accept roots that do not exist yet. Until a root exists, its nearest existing ancestor is
+    /// watched, and once it is created it is reported as created and watched like the others.
+    pub wait_for_missing_roots: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            events: ReportedEvents::default(),
+            debounce: Duration::from_millis(100),
+            debounce_mode: DebounceMode::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            respect_ignore_files: false,
+            backend: Backend::default(),
+            watch_limit_policy: WatchLimitPolicy::default(),
+            hash_contents_up_to: None,
+            state_file: None,
+            save_state_every: None,
+            detect_atomic_saves: false,
+            rescan_on_overflow: false,
+            rewatch_removed_roots: false,
+            wait_for_missing_roots: false,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -37,9 +260,15 @@ pub enum FileWatcherError {
 
     #[error("Attempted to remove a watch that does not exist for path: '{0}'")]
     WatchNotFound(PathBuf),
+
+    #[error("Invalid glob pattern: {0}")]
+    InvalidGlob(String),
 }
 
-fn map_notify_error_to_file_watcher_error(e: notify::Error, path: &Path) -> FileWatcherError {
+pub(crate) fn map_notify_error_to_file_watcher_error(
+    e: notify::Error,
+    path: &Path,
+) -> FileWatcherError {
     use notify::ErrorKind;
     match e.kind {
         ErrorKind::PathNotFound => FileWatcherError::PathNotFound(path.to_path_buf()),
@@ -52,30 +281,409 @@ fn map_notify_error_to_file_watcher_error(e: notify::Error, path: &Path) -> File
     }
 }
 
+/// The backend watcher, shared with the event loop so it can watch new directories of depth limited roots.
+pub(crate) type SharedWatcher = Arc<Mutex<BackendWatcher>>;
+
+/// Keeps a watch started with the `start_watch` functions running. Dropping it stops the watch.
+#[derive(Debug)]
+pub struct WatchHandle {
+    _watcher: SharedWatcher,
+}
+
 #[derive(Debug)]
 pub struct FileWatcher {
     pub receiver: Receiver<ChangeMessage>,
-    pub watcher: RecommendedWatcher, // keeps watcher alive
+    /// Used instead of `receiver` with [`DebounceMode::Batch`].
+    batches: Receiver<Vec<ChangeMessage>>,
+    /// Changes passed over by the scoped queries, kept for the other callers.
+    kept: Mutex<Vec<ChangeMessage>>,
+    errors: Receiver<FileWatcherError>,
+    watcher: SharedWatcher, // keeps watcher alive
+    roots: Vec<WatchRoot>,
+    loop_sender: mpsc::Sender<LoopInput>,
+    notifier: Arc<Notifier>,
+    dispatcher: Option<Dispatcher>,
+    state_file: Option<Arc<StateFile>>,
+    wait_for_missing_roots: bool,
 }
 
 impl FileWatcher {
     /// # Errors
     ///
     pub fn new(watch_path: &Path) -> Result<Self, FileWatcherError> {
-        let (watcher, receiver) = start_watch(watch_path)?;
-        while let Ok(_found) = receiver.recv() {
+        Self::builder(watch_path).build()
+    }
+
+    #[must_use]
+    pub fn builder(watch_path: &Path) -> FileWatcherBuilder {
+        FileWatcherBuilder::new(watch_path)
+    }
+
+    /// # Errors
+    ///
+    pub fn with_options(
+        watch_path: &Path,
+        options: &WatchOptions,
+    ) -> Result<Self, FileWatcherError> {
+        Self::with_roots(&[WatchRoot::recursive(watch_path)], options)
+    }
+
+    /// # Errors
+    ///
+    pub fn with_roots(
+        roots: &[WatchRoot],
+        options: &WatchOptions,
+    ) -> Result<Self, FileWatcherError> {
+        let handles = spawn_watch(roots, options, false)?;
+        while let Ok(_found) = handles.receiver.recv() {}
+        while let Ok(_found) = handles.batches.recv() {}
+        let file_watcher = Self {
+            receiver: handles.receiver,
+            batches: handles.batches,
+            kept: Mutex::new(Vec::new()),
+            errors: handles.errors,
+            watcher: handles.watcher,
+            roots: roots.to_vec(),
+            loop_sender: handles.loop_sender,
+            notifier: handles.notifier,
+            dispatcher: None,
+            state_file: handles.state_file,
+            wait_for_missing_roots: options.wait_for_missing_roots,
+        };
+
+        if let Some(state_file) = &file_watcher.state_file {
+            let missed = state_file.changes_since_saved(roots);
+            let (done, delivered) = mpsc::channel();
+            file_watcher
+                .loop_sender
+                .send(LoopInput::Replay(missed, done))
+                .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))?;
+            // so the changes are pending as soon as the watcher is returned
+            delivered
+                .recv()
+                .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))?;
+        }
+
+        Ok(file_watcher)
+    }
+
+    /// Writes the state file now, instead of waiting for the watcher to be dropped.
+    /// Does nothing if no state file was configured.
+    ///
+    /// # Errors
+    ///
+    pub fn save_state(&self) -> Result<(), FileWatcherError> {
+        self.state_file
+            .as_ref()
+            .map_or(Ok(()), |state_file| state_file.save(&self.roots))
+    }
+
+    /// Starts watching another root. Adding a path that is already a root changes its recursive mode.
+    ///
+    /// # Errors
+    ///
+    pub fn add_path(
+        &mut self,
+        path: &Path,
+        recursive_mode: RecursiveMode,
+    ) -> Result<(), FileWatcherError> {
+        self.add_root(WatchRoot::new(path, recursive_mode))
+    }
+
+    /// Like [`Self::add_path`], for roots with a depth limit.
+    ///
+    /// # Errors
+    ///
+    pub fn add_root(&mut self, root: WatchRoot) -> Result<(), FileWatcherError> {
+        let mut roots = self.roots.clone();
+        roots.retain(|existing| existing.path != root.path);
+        roots.push(root);
+        self.set_roots(roots)
+    }
+
+    /// Stops watching a root that was given at construction or with [`Self::add_path`].
+    ///
+    /// # Errors
+    ///
+    /// [`FileWatcherError::WatchNotFound`] if `path` is not one of the roots.
+    pub fn remove_path(&mut self, path: &Path) -> Result<(), FileWatcherError> {
+        if !self.roots.iter().any(|root| root.path == path) {
+            return Err(FileWatcherError::WatchNotFound(path.to_path_buf()));
         }
-        Ok(Self { receiver, watcher })
+        let mut roots = self.roots.clone();
+        roots.retain(|root| root.path != path);
+        self.set_roots(roots)
+    }
+
+    fn set_roots(&mut self, roots: Vec<WatchRoot>) -> Result<(), FileWatcherError> {
+        {
+            let mut watcher = lock_watcher(&self.watcher)?;
+            // the event loop watches missing roots once they exist
+            let (old_roots, new_roots) = if self.wait_for_missing_roots {
+                (existing_roots(&self.roots), existing_roots(&roots))
+            } else {
+                (self.roots.clone(), roots.clone())
+            };
+            if let Err(e) = update_backend_watches(&mut watcher, &old_roots, &new_roots) {
+                // put back what was there before, so the watcher stays consistent with `self.roots`
+                let _ = update_backend_watches(&mut watcher, &new_roots, &old_roots);
+                return Err(e);
+            }
+        }
+
+        self.loop_sender
+            .send(LoopInput::RootsChanged(roots.clone()))
+            .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))?;
+        self.roots = roots;
+
+        Ok(())
+    }
+
+    /// Returns true if the filesystem is scanned periodically instead of observed through native events.
+    #[must_use]
+    pub fn is_polling(&self) -> bool {
+        self.watcher
+            .lock()
+            .is_ok_and(|watcher| watcher.is_polling())
+    }
+
+    /// Returns true if the native watch limit was reached, and parts of the tree are polled instead.
+    /// Only happens with [`WatchLimitPolicy::PollRemaining`].
+    #[must_use]
+    pub fn is_degraded(&self) -> bool {
+        self.watcher
+            .lock()
+            .is_ok_and(|watcher| watcher.is_degraded())
+    }
+
+    /// The directories whose subtrees are polled because the native watch limit was reached.
+    #[must_use]
+    pub fn polled_paths(&self) -> Vec<PathBuf> {
+        self.watcher
+            .lock()
+            .map(|watcher| watcher.polled_paths())
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn roots(&self) -> &[WatchRoot] {
+        &self.roots
+    }
+
+    /// The root a change was reported through. When roots overlap, this is the most specific one.
+    #[must_use]
+    pub fn root_of(&self, change: &ChangeMessage) -> Option<&Path> {
+        roots::root_for(&self.roots, change.path()).map(|root| root.path.as_path())
     }
 
     #[must_use]
     pub fn has_changed(&self) -> bool {
-        let mut result = false;
-        while let Ok(_found) = self.receiver.recv() {
-            result = true;
+        !self.take_changes().is_empty()
+    }
+
+    /// Returns all changes that have arrived since the last call, in the order they were reported.
+    /// With [`DebounceMode::Batch`], the changes of all pending batches.
+    #[must_use]
+    pub fn take_changes(&self) -> Vec<ChangeMessage> {
+        self.take_changes_where(|_| true)
+    }
+
+    /// Like [`Self::has_changed`], but only consumes the changes at or below `path`.
+    /// The other changes stay pending.
+    #[must_use]
+    pub fn has_changed_under(&self, path: &Path) -> bool {
+        !self.take_changes_under(path).is_empty()
+    }
+
+    /// Like [`Self::take_changes`], but only takes the changes at or below `path`.
+    /// A rename is taken if either side of it is below `path`. The other changes stay pending.
+    #[must_use]
+    pub fn take_changes_under(&self, path: &Path) -> Vec<ChangeMessage> {
+        self.take_changes_where(|change| match change {
+            ChangeMessage::Renamed { from, to } => from.starts_with(path) || to.starts_with(path),
+            _ => change.path().starts_with(path),
+        })
+    }
+
+    /// Like [`Self::take_changes`], but only takes the changes to paths matching `pattern`,
+    /// relative to the root the change was reported through. The other changes stay pending.
+    ///
+    /// # Errors
+    ///
+    /// [`FileWatcherError::InvalidGlob`] if `pattern` is not a valid glob.
+    pub fn take_changes_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<Vec<ChangeMessage>, FileWatcherError> {
+        let filter = PathFilter::new(&[pattern.to_string()], &[])?;
+        Ok(self.take_changes_where(|change| {
+            self.root_of(change)
+                .is_some_and(|root| filter.matches(change, root))
+        }))
+    }
+
+    fn take_changes_where(&self, wanted: impl Fn(&ChangeMessage) -> bool) -> Vec<ChangeMessage> {
+        let Ok(mut kept) = self.kept.lock() else {
+            return Vec::new();
+        };
+        while let Ok(found) = self.receiver.recv() {
+            kept.push(found);
+        }
+        while let Ok(batch) = self.batches.recv() {
+            kept.extend(batch);
         }
 
-        result
+        let (changes, rest) = std::mem::take(&mut *kept).into_iter().partition(wanted);
+        *kept = rest;
+
+        changes
+    }
+
+    /// The oldest pending change, if there is one.
+    #[cfg_attr(not(feature = "stream"), allow(dead_code))]
+    pub(crate) fn next_change(&self) -> Option<ChangeMessage> {
+        let mut kept = self.kept.lock().ok()?;
+        if !kept.is_empty() {
+            return Some(kept.remove(0));
+        }
+        if let Ok(change) = self.receiver.recv() {
+            return Some(change);
+        }
+        let mut batch = self.batches.recv().ok()?.into_iter();
+        let change = batch.next();
+        kept.extend(batch);
+
+        change
+    }
+
+    /// Returns the errors the backend reported while watching, since the last call. A watcher that
+    /// reports errors may have missed changes, so callers may want to rescan or restart it.
+    #[must_use]
+    pub fn take_errors(&self) -> Vec<FileWatcherError> {
+        let mut errors = Vec::new();
+        while let Ok(error) = self.errors.recv() {
+            errors.push(error);
+        }
+
+        errors
+    }
+
+    /// Returns the oldest pending batch, with one change per path and the net effect of the
+    /// events within it. Only used with [`DebounceMode::Batch`].
+    #[must_use]
+    pub fn take_batch(&self) -> Option<Vec<ChangeMessage>> {
+        self.batches.recv().ok()
+    }
+
+    /// Blocks the thread until a batch is complete, and returns it.
+    #[must_use]
+    pub fn wait_for_batch(&self) -> Vec<ChangeMessage> {
+        self.wait_for_batch_until(None).unwrap_or_default()
+    }
+
+    /// Like [`Self::wait_for_batch`], but gives up after `timeout`.
+    #[must_use]
+    pub fn wait_for_batch_timeout(&self, timeout: Duration) -> Option<Vec<ChangeMessage>> {
+        self.wait_for_batch_until(Instant::now().checked_add(timeout))
+    }
+
+    fn wait_for_batch_until(&self, deadline: Option<Instant>) -> Option<Vec<ChangeMessage>> {
+        loop {
+            let generation = self.notifier.generation();
+            if let Some(batch) = self.take_batch() {
+                return Some(batch);
+            }
+            if !self.notifier.wait(generation, deadline) {
+                return None;
+            }
+        }
+    }
+
+    /// Runs `handler` for every change, on a thread dedicated to handlers.
+    /// The changes are still delivered to [`Self::take_changes`] and the other queries as well.
+    ///
+    /// # Errors
+    ///
+    pub fn on_change(
+        &mut self,
+        handler: impl FnMut(&ChangeMessage) + Send + 'static,
+    ) -> Result<HandlerId, FileWatcherError> {
+        self.add_handler(None, Box::new(handler))
+    }
+
+    /// Like [`Self::on_change`], but only for changes to paths matching `pattern`,
+    /// relative to the root the change was reported through.
+    ///
+    /// # Errors
+    ///
+    /// [`FileWatcherError::InvalidGlob`] if `pattern` is not a valid glob.
+    pub fn on_change_matching(
+        &mut self,
+        pattern: &str,
+        handler: impl FnMut(&ChangeMessage) + Send + 'static,
+    ) -> Result<HandlerId, FileWatcherError> {
+        let scope = PathFilter::new(&[pattern.to_string()], &[])?;
+        self.add_handler(Some(scope), Box::new(handler))
+    }
+
+    /// Returns false if there was no handler with that id.
+    pub fn remove_handler(&mut self, id: HandlerId) -> bool {
+        self.dispatcher
+            .as_ref()
+            .is_some_and(|dispatcher| dispatcher.remove(id))
+    }
+
+    fn add_handler(
+        &mut self,
+        scope: Option<PathFilter>,
+        handler: ChangeHandler,
+    ) -> Result<HandlerId, FileWatcherError> {
+        let dispatcher = match self.dispatcher.take() {
+            Some(dispatcher) => dispatcher,
+            None => {
+                let (dispatcher, dispatch) = Dispatcher::spawn()?;
+                self.loop_sender
+                    .send(LoopInput::StartDispatch(dispatch))
+                    .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))?;
+                dispatcher
+            }
+        };
+
+        self.dispatcher.insert(dispatcher).add(scope, handler)
+    }
+
+    /// Blocks the thread until at least one change has arrived, and returns all pending changes.
+    #[must_use]
+    pub fn wait_for_change(&self) -> Vec<ChangeMessage> {
+        self.wait_until(None)
+    }
+
+    /// Like [`Self::wait_for_change`], but gives up after `timeout`. Returns an empty batch on timeout.
+    #[must_use]
+    pub fn wait_for_change_timeout(&self, timeout: Duration) -> Vec<ChangeMessage> {
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Vec<ChangeMessage> {
+        loop {
+            // read the generation before looking, so a change sent in between is not missed
+            let generation = self.notifier.generation();
+            let changes = self.take_changes();
+            if !changes.is_empty() {
+                return changes;
+            }
+            if !self.notifier.wait(generation, deadline) {
+                return Vec::new();
+            }
+        }
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_state() {
+            error!(error = ?e, "Failed to save watch state");
+        }
     }
 }
 
@@ -86,56 +694,175 @@ impl FileWatcher {
 ///
 pub fn start_watch(
     watch_path: &Path,
-) -> Result<(RecommendedWatcher, Receiver<ChangeMessage>), FileWatcherError> {
+) -> Result<(WatchHandle, Receiver<ChangeMessage>), FileWatcherError> {
+    start_watch_with_options(watch_path, &WatchOptions::default())
+}
+
+/// # Errors
+///
+pub fn start_watch_with_options(
+    watch_path: &Path,
+    options: &WatchOptions,
+) -> Result<(WatchHandle, Receiver<ChangeMessage>), FileWatcherError> {
+    start_watch_roots(&[WatchRoot::recursive(watch_path)], options)
+}
+
+/// # Errors
+///
+pub fn start_watch_roots(
+    roots: &[WatchRoot],
+    options: &WatchOptions,
+) -> Result<(WatchHandle, Receiver<ChangeMessage>), FileWatcherError> {
+    let handles = spawn_watch(roots, options, true)?;
+    let handle = WatchHandle {
+        _watcher: handles.watcher,
+    };
+    Ok((handle, handles.receiver))
+}
+
+/// Everything a [`FileWatcher`] needs to talk to a running watch.
+struct WatchHandles {
+    watcher: SharedWatcher,
+    receiver: Receiver<ChangeMessage>,
+    batches: Receiver<Vec<ChangeMessage>>,
+    errors: Receiver<FileWatcherError>,
+    loop_sender: mpsc::Sender<LoopInput>,
+    notifier: Arc<Notifier>,
+    state_file: Option<Arc<StateFile>>,
+}
+
+fn spawn_watch(
+    roots: &[WatchRoot],
+    options: &WatchOptions,
+    flatten_batches: bool,
+) -> Result<WatchHandles, FileWatcherError> {
     let (sender, receiver) = Channel::create();
+    let (batch_sender, batches) = Channel::create();
+    let (error_sender, errors) = Channel::create();
+    let notifier = Arc::new(Notifier::default());
+    let (loop_sender, loop_receiver) = mpsc::channel::<LoopInput>();
 
-    let mut last_event = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
-    let debounce_duration = Duration::from_millis(100);
-
-    let owned_watch_path = watch_path.to_path_buf();
-
-    let mut watcher = notify::recommended_watcher(move |res: NotifyResult<Event> | match res {
-        Ok(event) if matches!(event.kind,
-            EventKind::Modify(ModifyKind::Data(_))
-          | EventKind::Modify(ModifyKind::Any)
-          ) =>
-            {
-                let now = Instant::now();
-                if now.duration_since(last_event) >= debounce_duration {
-                    if let Err(e) = sender.send(ChangeMessage::SomeKindOfChange) {
-                        error!(
-                        error = ?e,
-                        "FileWatcher internal channel send error: receiver likely dropped"
-                    );
-                    }
-                    last_event = now;
-                }
-            }
-        Ok(_) => {
-            // ignore metadata, attrib, open, etc.
-        }
+    let (existing, missing) = if options.wait_for_missing_roots {
+        roots.iter().cloned().partition(|root| root.path.exists())
+    } else {
+        (roots.to_vec(), Vec::new())
+    };
+    let watcher = start_backend(
+        options.backend,
+        options.watch_limit_policy,
+        &existing,
+        &loop_sender,
+    )?;
+    let watcher = Arc::new(Mutex::new(watcher));
+    let state_file = options
+        .state_file
+        .as_deref()
+        .map(|path| Arc::new(StateFile::new(path, options.hash_contents_up_to)));
 
-        Err(e) => {
-            error!(
-                error = ?e,
-                path = ?owned_watch_path,
-                "FileWatcher internal background watch error"
-            );
-        }
+    let mut event_loop = EventLoop {
+        sender,
+        batch_sender,
+        flatten_batches,
+        error_sender,
+        notifier: Arc::clone(&notifier),
+        events: options.events,
+        filter: PathFilter::new(&options.include, &options.exclude)?,
+        ignore_rules: options
+            .respect_ignore_files
+            .then(|| IgnoreRules::load(roots)),
+        content_filter: options.hash_contents_up_to.map(ContentFilter::new),
+        debouncer: Debouncer::new(options.debounce_mode, options.debounce),
+        renames: RenamePairer::default(),
+        atomic_saves: options
+            .detect_atomic_saves
+            .then(AtomicSaveDetector::default),
+        rescanner: options
+            .rescan_on_overflow
+            .then(|| Rescanner::new(&existing, options.hash_contents_up_to)),
+        rewatch_removed_roots: options.rewatch_removed_roots,
+        missing_roots: MissingRoots::default(),
+        wait_for_missing_roots: options.wait_for_missing_roots,
+        roots: roots.to_vec(),
+        watcher: Arc::downgrade(&watcher),
+        dispatch: None,
+        periodic_save: state_file.as_ref().zip(options.save_state_every).map(
+            |(state_file, interval)| PeriodicSave {
+                state_file: Arc::clone(state_file),
+                interval,
+                next: Instant::now().checked_add(interval),
+                saving: None,
+            },
+        ),
+    };
+    for root in missing {
+        event_loop.missing_roots.add(root, true);
+    }
+    // watch the ancestors, or the roots themselves if they were created in the meantime
+    event_loop.advance_missing_roots();
+
+    thread::Builder::new()
+        .name("fs-change-detector".to_string())
+        .spawn(move || event_loop.run(&loop_receiver))
+        .map_err(|e| FileWatcherError::IoError(e.to_string()))?;
+
+    debug!(roots = ?roots, "Successfully started file watcher");
+
+    Ok(WatchHandles {
+        watcher,
+        receiver,
+        batches,
+        errors,
+        loop_sender,
+        notifier,
+        state_file,
     })
-    .map_err(|e| {
-        error!(error = ?e, path = ?watch_path, "Failed to initialize watcher");
-        map_notify_error_to_file_watcher_error(e, watch_path)
-    })?;
+}
+
+fn existing_roots(roots: &[WatchRoot]) -> Vec<WatchRoot> {
+    roots
+        .iter()
+        .filter(|root| root.path.exists())
+        .cloned()
+        .collect()
+}
 
+fn lock_watcher(
+    watcher: &SharedWatcher,
+) -> Result<std::sync::MutexGuard<'_, BackendWatcher>, FileWatcherError> {
     watcher
-        .watch(watch_path, RecursiveMode::Recursive)
-        .map_err(|e| {
-            error!(error = ?e, path = ?watch_path, "Failed to start watching path");
-            map_notify_error_to_file_watcher_error(e, watch_path)
-        })?;
+        .lock()
+        .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))
+}
 
-    debug!(path = ?watch_path, "Successfully started file watcher");
+/// Registers and unregisters backend watches so they go from covering `old_roots` to `new_roots`.
+pub(crate) fn update_backend_watches(
+    watcher: &mut BackendWatcher,
+    old_roots: &[WatchRoot],
+    new_roots: &[WatchRoot],
+) -> Result<(), FileWatcherError> {
+    let old_watches = roots::backend_watches(old_roots);
+    let new_watches = roots::backend_watches(new_roots);
+
+    // Unwatch first: a backend may share the watch of a nested directory with a new recursive root
+    for (path, _) in old_watches
+        .iter()
+        .filter(|watch| !new_watches.contains(watch))
+    {
+        if let Err(e) = watcher.unwatch(path) {
+            // the path may be gone already, which removes the watch as well
+            debug!(error = ?e, path = ?path, "Failed to stop watching path");
+        }
+    }
+
+    for (path, recursive_mode) in new_watches
+        .iter()
+        .filter(|watch| !old_watches.contains(watch))
+    {
+        watcher.watch(path, *recursive_mode).map_err(|e| {
+            error!(error = ?e, path = ?path, "Failed to start watching path");
+            map_notify_error_to_file_watcher_error(e, path)
+        })?;
+    }
 
-    Ok((watcher, receiver))
+    Ok(())
 }
@@ -0,0 +1,110 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::BackendWatcher;
+use crate::roots::WatchRoot;
+use notify::RecursiveMode;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+struct MissingRoot {
+    root: WatchRoot,
+    /// Watch the root again once it exists. Otherwise it stays missing until the roots change.
+    wait: bool,
+}
+
+/// Roots whose path does not exist. While waiting for one, its nearest existing ancestor is
+/// watched, and the watch moves down as the path components are created.
+#[derive(Debug, Default)]
+pub(crate) struct MissingRoots {
+    roots: Vec<MissingRoot>,
+    /// The ancestor directories that have a watch of their own for this.
+    ancestor_watches: BTreeSet<PathBuf>,
+}
+
+impl MissingRoots {
+    pub(crate) fn contains(&self, root: &WatchRoot) -> bool {
+        self.roots.iter().any(|missing| missing.root == *root)
+    }
+
+    pub(crate) fn add(&mut self, root: WatchRoot, wait: bool) {
+        if !self.contains(&root) {
+            self.roots.push(MissingRoot { root, wait });
+        }
+    }
+
+    /// Forgets the roots that are no longer watched at all.
+    pub(crate) fn retain(&mut self, roots: &[WatchRoot]) {
+        self.roots.retain(|missing| roots.contains(&missing.root));
+    }
+
+    /// Returns true if a change to `path` may bring one of the awaited roots closer to existing.
+    pub(crate) fn is_on_the_way(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .any(|missing| missing.wait && missing.root.path.starts_with(path))
+    }
+
+    /// Moves the ancestor watches as far down as the paths exist, and returns the awaited roots
+    /// that exist now. Those are no longer missing, and need watches of their own.
+    pub(crate) fn advance(
+        &mut self,
+        watcher: &mut BackendWatcher,
+        active_roots: &[WatchRoot],
+    ) -> Vec<WatchRoot> {
+        let mut appeared = Vec::new();
+        loop {
+            let (found, missing): (Vec<_>, Vec<_>) = std::mem::take(&mut self.roots)
+                .into_iter()
+                .partition(|missing| missing.wait && missing.root.path.exists());
+            self.roots = missing;
+            appeared.extend(found.into_iter().map(|found| found.root));
+
+            let wanted: BTreeSet<PathBuf> = self
+                .roots
+                .iter()
+                .filter(|missing| missing.wait)
+                .filter_map(|missing| nearest_existing_ancestor(&missing.root.path))
+                .filter(|ancestor| !is_watched_by(active_roots, ancestor))
+                .collect();
+
+            for stale in self
+                .ancestor_watches
+                .difference(&wanted)
+                .filter(|stale| !is_watched_by(active_roots, stale))
+            {
+                if let Err(e) = watcher.unwatch(stale) {
+                    // the directory may be gone already, which removes the watch as well
+                    debug!(error = ?e, path = ?stale, "Failed to stop watching ancestor");
+                }
+            }
+            let mut added = false;
+            for ancestor in wanted.difference(&self.ancestor_watches) {
+                match watcher.watch(ancestor, RecursiveMode::NonRecursive) {
+                    Ok(()) => added = true,
+                    Err(e) => warn!(error = ?e, path = ?ancestor, "Failed to watch ancestor"),
+                }
+            }
+            self.ancestor_watches = wanted;
+
+            // a component may have been created before its parent got a watch
+            if !added {
+                return appeared;
+            }
+        }
+    }
+}
+
+fn nearest_existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_dir())
+        .map(Path::to_path_buf)
+}
+
+fn is_watched_by(roots: &[WatchRoot], dir: &Path) -> bool {
+    roots.iter().any(|root| root.watches_directory(dir))
+}
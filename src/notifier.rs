@@ -0,0 +1,74 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use std::sync::{Condvar, Mutex};
+use std::task::Waker;
+use std::time::Instant;
+
+/// Lets the event loop wake up whoever is waiting for the next change,
+/// either a blocked thread or an async task.
+#[derive(Debug, Default)]
+pub(crate) struct Notifier {
+    waker: Mutex<Option<Waker>>,
+    /// Increased for every change sent, so waiters can tell if they missed one.
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Notifier {
+    /// Called by the event loop after a change has been sent.
+    pub(crate) fn notify(&self) {
+        if let Ok(mut generation) = self.generation.lock() {
+            *generation = generation.wrapping_add(1);
+            self.changed.notify_all();
+        }
+
+        if let Some(waker) = self.waker.lock().ok().and_then(|mut waker| waker.take()) {
+            waker.wake();
+        }
+    }
+
+    /// Wakes `waker` on the next change. Replaces any previously registered waker.
+    #[cfg_attr(not(feature = "stream"), allow(dead_code))]
+    pub(crate) fn register(&self, waker: &Waker) {
+        if let Ok(mut registered) = self.waker.lock() {
+            match registered.as_ref() {
+                Some(existing) if existing.will_wake(waker) => {}
+                _ => *registered = Some(waker.clone()),
+            }
+        }
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.lock().map_or(0, |generation| *generation)
+    }
+
+    /// Blocks until a change newer than `seen_generation` has been sent, or `deadline` has passed.
+    /// Returns false on timeout.
+    pub(crate) fn wait(&self, seen_generation: u64, deadline: Option<Instant>) -> bool {
+        let Ok(mut generation) = self.generation.lock() else {
+            return false;
+        };
+        while *generation == seen_generation {
+            generation = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    match self.changed.wait_timeout(generation, deadline - now) {
+                        Ok((generation, _)) => generation,
+                        Err(_) => return false,
+                    }
+                }
+                None => match self.changed.wait(generation) {
+                    Ok(generation) => generation,
+                    Err(_) => return false,
+                },
+            };
+        }
+
+        true
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::ChangeMessage;
+use notify::Event;
+use notify::event::RenameMode;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long the source of a rename waits for its destination. Both halves are read from the
+/// backend together, so this only has to cover the time between two reads.
+const PAIRING_WINDOW: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+struct PendingFrom {
+    path: PathBuf,
+    tracker: Option<usize>,
+    since: Instant,
+}
+
+/// Pairs the separate `From` and `To` halves of a rename, linked by their tracker, into one
+/// [`ChangeMessage::Renamed`]. A half without its partner was a move into or out of the watched tree.
+#[derive(Debug, Default)]
+pub(crate) struct RenamePairer {
+    pending: Vec<PendingFrom>,
+    /// Trackers of the `To` halves handled here, so the `Both` event the backend sends for them as well is dropped.
+    paired: HashSet<usize>,
+}
+
+impl RenamePairer {
+    pub(crate) fn push(
+        &mut self,
+        mode: RenameMode,
+        event: &Event,
+        now: Instant,
+    ) -> Vec<ChangeMessage> {
+        let tracker = event.tracker();
+        match mode {
+            RenameMode::From => {
+                self.pending
+                    .extend(event.paths.iter().map(|path| PendingFrom {
+                        path: path.clone(),
+                        tracker,
+                        since: now,
+                    }));
+                Vec::new()
+            }
+            RenameMode::To => {
+                if let Some(tracker) = tracker {
+                    // also when the source expired, or the `Both` would report the rename again
+                    self.paired.insert(tracker);
+                }
+                event
+                    .paths
+                    .iter()
+                    .map(|to| match self.take_from(tracker) {
+                        Some(from) => ChangeMessage::Renamed {
+                            from,
+                            to: to.clone(),
+                        },
+                        // moved into the tree
+                        None => ChangeMessage::Created(to.clone()),
+                    })
+                    .collect()
+            }
+            RenameMode::Both if tracker.is_some_and(|tracker| self.paired.remove(&tracker)) => {
+                Vec::new()
+            }
+            _ => ChangeMessage::from_event(event),
+        }
+    }
+
+    fn take_from(&mut self, tracker: Option<usize>) -> Option<PathBuf> {
+        let index = match tracker {
+            Some(_) => self
+                .pending
+                .iter()
+                .position(|pending| pending.tracker == tracker)?,
+            // without trackers, the halves of a rename follow each other
+            None => self
+                .pending
+                .iter()
+                .rposition(|pending| pending.tracker.is_none())?,
+        };
+        Some(self.pending.remove(index).path)
+    }
+
+    /// The point in time where unpaired sources should be given up on, if there are any.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .iter()
+            .map(|pending| pending.since + PAIRING_WINDOW)
+            .min()
+    }
+
+    /// Sources that did not get a destination in time were moved out of the tree.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<ChangeMessage> {
+        let (expired, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| pending.since + PAIRING_WINDOW <= now);
+        self.pending = pending;
+        expired
+            .into_iter()
+            .map(|pending: PendingFrom| ChangeMessage::Removed(pending.path))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::EventKind;
+    use notify::event::ModifyKind;
+
+    fn rename(mode: RenameMode, paths: &[&str]) -> Event {
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(mode))).set_tracker(1);
+        paths
+            .iter()
+            .fold(event, |event, path| event.add_path(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn late_destination_is_created_once() {
+        let mut pairer = RenamePairer::default();
+        let start = Instant::now();
+        assert!(
+            pairer
+                .push(RenameMode::From, &rename(RenameMode::From, &["a"]), start)
+                .is_empty()
+        );
+
+        let late = start + PAIRING_WINDOW;
+        assert_eq!(pairer.expire(late), [ChangeMessage::Removed("a".into())]);
+        assert_eq!(
+            pairer.push(RenameMode::To, &rename(RenameMode::To, &["b"]), late),
+            [ChangeMessage::Created("b".into())]
+        );
+        assert!(
+            pairer
+                .push(
+                    RenameMode::Both,
+                    &rename(RenameMode::Both, &["a", "b"]),
+                    late
+                )
+                .is_empty()
+        );
+    }
+}
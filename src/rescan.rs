@@ -0,0 +1,79 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::ChangeMessage;
+use crate::roots::WatchRoot;
+use crate::snapshot::Snapshot;
+use notify::{Event, EventKind};
+use tracing::warn;
+
+/// Keeps a snapshot of every root up to date with the observed events, so the changes missed
+/// when the backend drops events can be found by comparing against a fresh capture.
+#[derive(Debug)]
+pub(crate) struct Rescanner {
+    max_hash_size: Option<u64>,
+    /// Each snapshot with the root it was captured for.
+    snapshots: Vec<(WatchRoot, Snapshot)>,
+}
+
+impl Rescanner {
+    pub(crate) fn new(roots: &[WatchRoot], max_hash_size: Option<u64>) -> Self {
+        let mut rescanner = Self {
+            max_hash_size,
+            snapshots: Vec::new(),
+        };
+        rescanner.set_roots(roots);
+        rescanner
+    }
+
+    /// Captures the roots that are new, and forgets the ones that are gone.
+    pub(crate) fn set_roots(&mut self, roots: &[WatchRoot]) {
+        self.snapshots
+            .retain(|(captured, _)| roots.contains(captured));
+        for root in roots {
+            if self.snapshots.iter().any(|(captured, _)| captured == root) {
+                continue;
+            }
+            match Snapshot::capture_root(root, self.max_hash_size) {
+                Ok(snapshot) => self.snapshots.push((root.clone(), snapshot)),
+                Err(e) => {
+                    warn!(error = ?e, root = ?root.path, "Failed to capture root for rescans");
+                }
+            }
+        }
+    }
+
+    pub(crate) fn observe(&mut self, event: &Event) {
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) | EventKind::Any
+        ) {
+            return;
+        }
+        for (root, snapshot) in &mut self.snapshots {
+            for path in &event.paths {
+                snapshot.refresh(root, path, self.max_hash_size);
+            }
+        }
+    }
+
+    /// The changes below `root` that were not observed, or `None` if the root could not be captured.
+    pub(crate) fn rescan(&mut self, root: &WatchRoot) -> Option<Vec<ChangeMessage>> {
+        let current = match Snapshot::capture_root(root, self.max_hash_size) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!(error = ?e, root = ?root.path, "Failed to rescan root");
+                return None;
+            }
+        };
+        let (_, previous) = self
+            .snapshots
+            .iter_mut()
+            .find(|(captured, _)| captured == root)?;
+        let changes = previous.diff(&current).changes();
+        *previous = current;
+
+        Some(changes)
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use notify::RecursiveMode;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A directory or file watched by a [`crate::FileWatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchRoot {
+    pub path: PathBuf,
+    pub recursive_mode: RecursiveMode,
+    /// For recursive roots, how many directory levels below the root get watches.
+    /// `Some(0)` watches only the root directory itself. Ignored for non-recursive roots.
+    pub max_depth: Option<usize>,
+}
+
+impl WatchRoot {
+    #[must_use]
+    pub fn new(path: &Path, recursive_mode: RecursiveMode) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            recursive_mode,
+            max_depth: None,
+        }
+    }
+
+    /// Limits a recursive root to directories at most `max_depth` levels below it.
+    #[must_use]
+    pub const fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// The directory depth limit, if this root is recursive and has one.
+    const fn depth_limit(&self) -> Option<usize> {
+        match self.recursive_mode {
+            RecursiveMode::Recursive => self.max_depth,
+            RecursiveMode::NonRecursive => Some(0),
+        }
+    }
+
+    /// Returns true if `dir` should have a watch of its own, because it is within the depth limit of this root.
+    pub(crate) fn wants_directory_watch(&self, dir: &Path) -> bool {
+        match (self.recursive_mode, self.max_depth) {
+            (RecursiveMode::Recursive, Some(max_depth)) => {
+                depth_below(&self.path, dir).is_some_and(|depth| depth <= max_depth)
+            }
+            _ => false,
+        }
+    }
+
+    #[must_use]
+    pub fn recursive(path: &Path) -> Self {
+        Self::new(path, RecursiveMode::Recursive)
+    }
+
+    /// Returns true if changes to `path` are seen through this root.
+    #[must_use]
+    pub fn covers(&self, path: &Path) -> bool {
+        match (depth_below(&self.path, path), self.depth_limit()) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            // files in the deepest watched directories are one level further down
+            (Some(depth), Some(max_depth)) => depth <= max_depth + 1,
+        }
+    }
+
+    /// Returns true if the backend watches `dir` for this root, so changes directly inside it are seen.
+    pub(crate) fn watches_directory(&self, dir: &Path) -> bool {
+        match (depth_below(&self.path, dir), self.depth_limit()) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(depth), Some(max_depth)) => depth <= max_depth,
+        }
+    }
+
+    /// Returns true if everything seen through this root is already seen through `other`.
+    fn is_covered_by(&self, other: &Self) -> bool {
+        other.depth_limit().is_none() && self.path.starts_with(&other.path) && self != other
+    }
+}
+
+/// Number of path components from `root` down to `path`, or `None` if `path` is not below `root`.
+fn depth_below(root: &Path, path: &Path) -> Option<usize> {
+    path.strip_prefix(root)
+        .ok()
+        .map(|relative| relative.components().count())
+}
+
+/// The most specific root that covers `path`.
+pub(crate) fn root_for<'a>(roots: &'a [WatchRoot], path: &Path) -> Option<&'a WatchRoot> {
+    roots
+        .iter()
+        .filter(|root| root.covers(path))
+        .max_by_key(|root| root.path.components().count())
+}
+
+/// The watches the backend needs for `roots`. Roots that are inside another recursive
+/// root are left out, so overlapping roots do not produce duplicate events. Depth limited
+/// roots are expanded into a non-recursive watch for each directory within the limit.
+pub(crate) fn backend_watches(roots: &[WatchRoot]) -> Vec<(PathBuf, RecursiveMode)> {
+    let mut watches: Vec<(PathBuf, RecursiveMode)> = Vec::new();
+    for root in roots
+        .iter()
+        .filter(|root| !roots.iter().any(|other| root.is_covered_by(other)))
+    {
+        let mut add = |path: PathBuf, recursive_mode: RecursiveMode| match watches
+            .iter_mut()
+            .find(|(existing, _)| *existing == path)
+        {
+            Some((_, existing_mode)) if recursive_mode == RecursiveMode::Recursive => {
+                *existing_mode = recursive_mode;
+            }
+            Some(_) => {}
+            None => watches.push((path, recursive_mode)),
+        };
+
+        let directories = if root.wants_directory_watch(&root.path) {
+            directories_within(&root.path, root.max_depth.unwrap_or_default())
+        } else {
+            Vec::new()
+        };
+
+        if directories.is_empty() {
+            add(root.path.clone(), root.recursive_mode);
+        } else {
+            for dir in directories {
+                add(dir, RecursiveMode::NonRecursive);
+            }
+        }
+    }
+
+    watches
+}
+
+/// `dir` itself and all directories at most `max_depth` levels below it.
+pub(crate) fn directories_within(dir: &Path, max_depth: usize) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .max_depth(max_depth)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_dir())
+        .map(walkdir::DirEntry::into_path)
+        .collect()
+}
@@ -0,0 +1,334 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::content_hash::Fingerprint;
+use crate::roots::WatchRoot;
+use crate::{ChangeMessage, FileWatcherError};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+use walkdir::WalkDir;
+
+/// The state of a single file or directory in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Hash of the contents, if the snapshot was captured with hashes and the file was small enough.
+    pub hash: Option<u64>,
+    /// Device and inode on Unix, used to recognize renames.
+    pub file_id: Option<(u64, u64)>,
+}
+
+impl SnapshotEntry {
+    fn of(path: &Path, metadata: &Metadata, max_hash_size: Option<u64>) -> Self {
+        let hash = max_hash_size
+            .filter(|_| metadata.is_file())
+            .and_then(|max_hash_size| Fingerprint::of(path, max_hash_size).ok())
+            .and_then(|fingerprint| match fingerprint {
+                Fingerprint::Contents(hash) => Some(hash),
+                Fingerprint::Metadata { .. } => None,
+            });
+        Self::from_metadata(metadata, hash)
+    }
+
+    fn from_metadata(metadata: &Metadata, hash: Option<u64>) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            hash,
+            file_id: file_id(metadata),
+        }
+    }
+
+    fn is_modified_in(&self, newer: &Self) -> bool {
+        if self.is_dir || newer.is_dir {
+            return self.is_dir != newer.is_dir;
+        }
+        match (self.hash, newer.hash) {
+            (Some(hash), Some(newer_hash)) => hash != newer_hash,
+            _ => self.size != newer.size || self.modified != newer.modified,
+        }
+    }
+
+    /// What [`Self::is_same_file`] can match on: the file id, or the contents of a file.
+    fn rename_keys(&self) -> impl Iterator<Item = RenameKey> {
+        let by_contents = match self.hash {
+            Some(hash) if !self.is_dir && self.size > 0 => {
+                Some(RenameKey::Contents(self.size, hash))
+            }
+            _ => None,
+        };
+        self.file_id
+            .map(RenameKey::FileId)
+            .into_iter()
+            .chain(by_contents)
+    }
+
+    /// Returns true if `other` looks like this entry moved to another path.
+    fn is_same_file(&self, other: &Self) -> bool {
+        if self.is_dir != other.is_dir {
+            return false;
+        }
+        match (self.file_id, other.file_id) {
+            // inodes are reused, so also check that nothing but the path changed
+            (Some(id), Some(other_id)) => {
+                id == other_id
+                    && (self.is_dir || (self.size == other.size && self.modified == other.modified))
+            }
+            _ => {
+                !self.is_dir
+                    && self.size > 0
+                    && self.size == other.size
+                    && self.hash.is_some()
+                    && self.hash == other.hash
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RenameKey {
+    FileId((u64, u64)),
+    Contents(u64, u64),
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+const fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The paths, sizes and modification times of everything below a directory, at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    root: PathBuf,
+    /// Keyed by path relative to `root`.
+    entries: BTreeMap<PathBuf, SnapshotEntry>,
+}
+
+impl Snapshot {
+    /// # Errors
+    ///
+    /// [`FileWatcherError::PathNotFound`] if `root` does not exist.
+    pub fn capture(root: &Path) -> Result<Self, FileWatcherError> {
+        Self::capture_entries(root, None)
+    }
+
+    /// Like [`Self::capture`], but also hashes the contents of files up to `max_hash_size` bytes.
+    ///
+    /// # Errors
+    ///
+    /// [`FileWatcherError::PathNotFound`] if `root` does not exist.
+    pub fn capture_with_hashes(root: &Path, max_hash_size: u64) -> Result<Self, FileWatcherError> {
+        Self::capture_entries(root, Some(max_hash_size))
+    }
+
+    fn capture_entries(root: &Path, max_hash_size: Option<u64>) -> Result<Self, FileWatcherError> {
+        if !root.exists() {
+            return Err(FileWatcherError::PathNotFound(root.to_path_buf()));
+        }
+
+        let mut snapshot = Self {
+            root: root.to_path_buf(),
+            entries: BTreeMap::new(),
+        };
+        snapshot.insert_tree(WalkDir::new(root).min_depth(1), max_hash_size, |_| true);
+        Ok(snapshot)
+    }
+
+    /// Adds an entry for everything `walk` finds that `keep` accepts. Directories that are not
+    /// kept are not descended into.
+    fn insert_tree(
+        &mut self,
+        walk: WalkDir,
+        max_hash_size: Option<u64>,
+        keep: impl Fn(&Path) -> bool,
+    ) {
+        for entry in walk.into_iter().filter_entry(|entry| keep(entry.path())) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    debug!(error = ?e, "skipping unreadable entry in snapshot");
+                    continue;
+                }
+            };
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let relative = entry
+                .path()
+                .strip_prefix(&self.root)
+                .unwrap_or(entry.path());
+            self.entries.insert(
+                relative.to_path_buf(),
+                SnapshotEntry::of(entry.path(), &metadata, max_hash_size),
+            );
+        }
+    }
+
+    /// Captures what is seen through `root`, down to its depth limit.
+    pub(crate) fn capture_root(
+        root: &WatchRoot,
+        max_hash_size: Option<u64>,
+    ) -> Result<Self, FileWatcherError> {
+        let mut snapshot = Self::capture_entries(&root.path, max_hash_size)?;
+        snapshot
+            .entries
+            .retain(|relative, _| root.covers(&root.path.join(relative)));
+        Ok(snapshot)
+    }
+
+    /// Brings the entry of `path` up to date with the filesystem, after a change was observed there.
+    /// The snapshot must have been captured for `root`.
+    pub(crate) fn refresh(&mut self, root: &WatchRoot, path: &Path, max_hash_size: Option<u64>) {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return;
+        };
+        if relative.as_os_str().is_empty() {
+            return;
+        }
+        match std::fs::symlink_metadata(path) {
+            // a directory moved into place brings its contents along, without events of their own
+            Ok(metadata) if metadata.is_dir() => {
+                let relative = relative.to_path_buf();
+                self.entries
+                    .retain(|entry, _| entry == &relative || !entry.starts_with(&relative));
+                self.insert_tree(WalkDir::new(path), max_hash_size, |path| root.covers(path));
+            }
+            Ok(metadata) => {
+                self.entries.insert(
+                    relative.to_path_buf(),
+                    SnapshotEntry::of(path, &metadata, max_hash_size),
+                );
+            }
+            // a removed directory takes everything below it along
+            Err(_) => self.entries.retain(|entry, _| !entry.starts_with(relative)),
+        }
+    }
+
+    /// Creates a snapshot from entries that were captured earlier, for example read back from disk.
+    #[must_use]
+    pub const fn from_entries(root: PathBuf, entries: BTreeMap<PathBuf, SnapshotEntry>) -> Self {
+        Self { root, entries }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The entries, keyed by path relative to the root.
+    #[must_use]
+    pub const fn entries(&self) -> &BTreeMap<PathBuf, SnapshotEntry> {
+        &self.entries
+    }
+
+    /// What changed from this snapshot to `newer`. Paths in the diff are below the root of `newer`.
+    #[must_use]
+    pub fn diff(&self, newer: &Self) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        let mut removed: Vec<(&PathBuf, &SnapshotEntry)> = Vec::new();
+
+        for (path, entry) in &self.entries {
+            match newer.entries.get(path) {
+                None => removed.push((path, entry)),
+                Some(newer_entry) if entry.is_modified_in(newer_entry) => {
+                    diff.modified.push(newer.root.join(path));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let added: Vec<(&PathBuf, &SnapshotEntry)> = newer
+            .entries
+            .iter()
+            .filter(|(path, _)| !self.entries.contains_key(*path))
+            .collect();
+        // the candidates for a rename, so each removed entry only looks at the likely ones
+        let mut candidates: HashMap<RenameKey, Vec<usize>> = HashMap::new();
+        for (index, (_, entry)) in added.iter().enumerate() {
+            for key in entry.rename_keys() {
+                candidates.entry(key).or_default().push(index);
+            }
+        }
+
+        let mut taken = vec![false; added.len()];
+        for (path, entry) in removed {
+            let found = entry
+                .rename_keys()
+                .filter_map(|key| candidates.get(&key))
+                .flatten()
+                .copied()
+                .filter(|index| !taken[*index] && entry.is_same_file(added[*index].1))
+                .min();
+            match found {
+                Some(index) => {
+                    taken[index] = true;
+                    diff.renamed
+                        .push((newer.root.join(path), newer.root.join(added[index].0)));
+                }
+                None => diff.removed.push(newer.root.join(path)),
+            }
+        }
+        diff.added = added
+            .into_iter()
+            .zip(taken)
+            .filter(|(_, taken)| !taken)
+            .map(|((path, _), _)| newer.root.join(path))
+            .collect();
+
+        diff
+    }
+}
+
+/// The difference between two [`Snapshot`]s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    /// Pairs of `(from, to)`.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+impl SnapshotDiff {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.renamed.is_empty()
+    }
+
+    /// The diff expressed as the changes a [`crate::FileWatcher`] would have reported.
+    #[must_use]
+    pub fn changes(&self) -> Vec<ChangeMessage> {
+        let created = self.added.iter().cloned().map(ChangeMessage::Created);
+        let removed = self.removed.iter().cloned().map(ChangeMessage::Removed);
+        let modified = self.modified.iter().cloned().map(ChangeMessage::Modified);
+        let renamed = self
+            .renamed
+            .iter()
+            .map(|(from, to)| ChangeMessage::Renamed {
+                from: from.clone(),
+                to: to.clone(),
+            });
+
+        created
+            .chain(removed)
+            .chain(modified)
+            .chain(renamed)
+            .collect()
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::roots::WatchRoot;
+use crate::snapshot::{Snapshot, SnapshotEntry};
+use crate::{ChangeMessage, FileWatcherError};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+const HEADER: &str = "# fs-change-detector state v1";
+
+/// A manifest of the watched trees on disk, so changes made while no watcher ran can be found.
+///
+/// The file is line based. Each root starts with a `root` line, followed by one line per entry:
+/// kind, size, modification time, content hash, file id and the path relative to the root,
+/// separated by tabs. Paths that are not UTF-8 or contain a newline are left out.
+#[derive(Debug)]
+pub(crate) struct StateFile {
+    path: PathBuf,
+    hash_contents_up_to: Option<u64>,
+    /// The owning watcher and the periodic save may save at the same time. Held from capture to
+    /// rename, so a later capture is never overwritten by an earlier one.
+    write_lock: Mutex<()>,
+}
+
+impl StateFile {
+    pub(crate) fn new(path: &Path, hash_contents_up_to: Option<u64>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            hash_contents_up_to,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Captures the roots as they are now and writes them to the state file.
+    pub(crate) fn save(&self, roots: &[WatchRoot]) -> Result<(), FileWatcherError> {
+        let _guard = self
+            .write_lock
+            .lock()
+            .map_err(|e| FileWatcherError::InternalChannelError(e.to_string()))?;
+        let snapshots = self.capture(roots);
+
+        // write next to the state file and rename, so a crash never leaves half a manifest
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let io_error = |e: std::io::Error| FileWatcherError::IoError(e.to_string());
+
+        let mut file = std::io::BufWriter::new(fs::File::create(&temp_path).map_err(io_error)?);
+        writeln!(file, "{HEADER}").map_err(io_error)?;
+        for snapshot in &snapshots {
+            write_snapshot(&mut file, snapshot).map_err(io_error)?;
+        }
+        file.into_inner()
+            .map_err(|e| io_error(e.into_error()))?
+            .sync_all()
+            .map_err(io_error)?;
+        fs::rename(&temp_path, &self.path).map_err(io_error)?;
+
+        debug!(path = ?self.path, "Saved watch state");
+        Ok(())
+    }
+
+    /// The changes from the saved manifest to the trees as they are now.
+    /// Roots that were not in the manifest report nothing.
+    pub(crate) fn changes_since_saved(&self, roots: &[WatchRoot]) -> Vec<ChangeMessage> {
+        let saved = match self.load() {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!(error = ?e, path = ?self.path, "Ignoring unreadable watch state");
+                return Vec::new();
+            }
+        };
+
+        let mut seen = HashSet::new();
+        let mut changes = Vec::new();
+        for current in self.capture(roots) {
+            let Some(previous) = saved
+                .iter()
+                .find(|previous| previous.root() == current.root())
+            else {
+                continue;
+            };
+            // overlapping roots see the same changes
+            changes.extend(
+                previous
+                    .diff(&current)
+                    .changes()
+                    .into_iter()
+                    .filter(|change| seen.insert(change.clone())),
+            );
+        }
+
+        changes
+    }
+
+    fn capture(&self, roots: &[WatchRoot]) -> Vec<Snapshot> {
+        roots
+            .iter()
+            .filter_map(|root| {
+                let snapshot = Snapshot::capture_root(root, self.hash_contents_up_to)
+                    .inspect_err(|e| debug!(error = ?e, root = ?root.path, "Not capturing root"))
+                    .ok()?;
+                let entries = snapshot
+                    .entries()
+                    .iter()
+                    .filter(|(relative, _)| is_storable(relative))
+                    .map(|(relative, entry)| (relative.clone(), entry.clone()))
+                    .collect();
+                Some(Snapshot::from_entries(root.path.clone(), entries))
+            })
+            .collect()
+    }
+
+    fn load(&self) -> Result<Vec<Snapshot>, FileWatcherError> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(FileWatcherError::IoError(e.to_string())),
+        };
+        let invalid =
+            |line: &str| FileWatcherError::IoError(format!("invalid state line '{line}'"));
+
+        let mut lines = contents.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(FileWatcherError::IoError(
+                "unknown state file format".to_string(),
+            ));
+        }
+
+        let mut snapshots = Vec::new();
+        let mut current: Option<(PathBuf, BTreeMap<PathBuf, SnapshotEntry>)> = None;
+        for line in lines {
+            if let Some(root) = line.strip_prefix("root\t") {
+                if let Some((root, entries)) = current.take() {
+                    snapshots.push(Snapshot::from_entries(root, entries));
+                }
+                current = Some((PathBuf::from(root), BTreeMap::new()));
+                continue;
+            }
+            let (_, entries) = current.as_mut().ok_or_else(|| invalid(line))?;
+            let (relative, entry) = parse_entry(line).ok_or_else(|| invalid(line))?;
+            entries.insert(relative, entry);
+        }
+        if let Some((root, entries)) = current {
+            snapshots.push(Snapshot::from_entries(root, entries));
+        }
+
+        Ok(snapshots)
+    }
+}
+
+/// Paths that survive the round trip through the line based format.
+fn is_storable(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| !path.contains('\n'))
+}
+
+fn write_snapshot(out: &mut impl Write, snapshot: &Snapshot) -> std::io::Result<()> {
+    let Some(root) = snapshot.root().to_str() else {
+        warn!(root = ?snapshot.root(), "Not saving state of a root that is not UTF-8");
+        return Ok(());
+    };
+    writeln!(out, "root\t{root}")?;
+    for (relative, entry) in snapshot.entries() {
+        let Some(relative) = relative.to_str() else {
+            continue;
+        };
+        let modified = entry
+            .modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let hash = entry
+            .hash
+            .map_or_else(|| "-".to_string(), |hash| format!("{hash:x}"));
+        let file_id = entry
+            .file_id
+            .map_or_else(|| "-".to_string(), |(dev, ino)| format!("{dev}:{ino}"));
+        writeln!(
+            out,
+            "{}\t{}\t{}.{:09}\t{hash}\t{file_id}\t{relative}",
+            if entry.is_dir { 'd' } else { 'f' },
+            entry.size,
+            modified.as_secs(),
+            modified.subsec_nanos(),
+        )?;
+    }
+    Ok(())
+}
+
+fn parse_entry(line: &str) -> Option<(PathBuf, SnapshotEntry)> {
+    let mut fields = line.splitn(6, '\t');
+    let is_dir = match fields.next()? {
+        "d" => true,
+        "f" => false,
+        _ => return None,
+    };
+    let size = fields.next()?.parse().ok()?;
+    let (secs, nanos) = fields.next()?.split_once('.')?;
+    let modified = SystemTime::UNIX_EPOCH
+        .checked_add(Duration::new(secs.parse().ok()?, nanos.parse().ok()?))?;
+    let hash = match fields.next()? {
+        "-" => None,
+        hash => Some(u64::from_str_radix(hash, 16).ok()?),
+    };
+    let file_id = match fields.next()? {
+        "-" => None,
+        file_id => {
+            let (dev, ino) = file_id.split_once(':')?;
+            Some((dev.parse().ok()?, ino.parse().ok()?))
+        }
+    };
+    let relative = PathBuf::from(fields.next()?);
+
+    Some((
+        relative,
+        SnapshotEntry {
+            is_dir,
+            size,
+            modified,
+            hash,
+            file_id,
+        },
+    ))
+}
+
+/// Saves the state file at a fixed interval, on a thread of its own so capturing the tree
+/// does not hold up the event loop.
+#[derive(Debug)]
+pub(crate) struct PeriodicSave {
+    pub(crate) state_file: Arc<StateFile>,
+    pub(crate) interval: Duration,
+    /// `None` if the interval is too long to ever come around.
+    pub(crate) next: Option<Instant>,
+    pub(crate) saving: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSave {
+    pub(crate) fn save_if_due(&mut self, roots: &[WatchRoot], now: Instant) {
+        if self.next.is_none_or(|next| now < next) {
+            return;
+        }
+        self.next = now.checked_add(self.interval);
+        if self
+            .saving
+            .as_ref()
+            .is_some_and(|saving| !saving.is_finished())
+        {
+            debug!("Previous save of watch state still running, skipping this one");
+            return;
+        }
+
+        let state_file = Arc::clone(&self.state_file);
+        let roots = roots.to_vec();
+        let spawned = thread::Builder::new()
+            .name("fs-change-detector-save".to_string())
+            .spawn(move || {
+                if let Err(e) = state_file.save(&roots) {
+                    warn!(error = ?e, "Failed to save watch state");
+                }
+            });
+        match spawned {
+            Ok(saving) => self.saving = Some(saving),
+            Err(e) => warn!(error = ?e, "Failed to start saving watch state"),
+        }
+    }
+}
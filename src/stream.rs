@@ -0,0 +1,67 @@
+/*
+ * Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/swamp/swamp
+ * Licensed under the MIT License. See LICENSE in the project root for license information.
+ */
+use crate::{ChangeMessage, FileWatcher};
+use futures_core::Stream;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The changes of a [`FileWatcher`] as an endless [`Stream`]. Does not depend on a specific runtime.
+#[derive(Debug)]
+pub struct ChangeStream {
+    file_watcher: FileWatcher,
+}
+
+impl ChangeStream {
+    #[must_use]
+    pub const fn new(file_watcher: FileWatcher) -> Self {
+        Self { file_watcher }
+    }
+
+    #[must_use]
+    pub const fn file_watcher(&self) -> &FileWatcher {
+        &self.file_watcher
+    }
+
+    pub const fn file_watcher_mut(&mut self) -> &mut FileWatcher {
+        &mut self.file_watcher
+    }
+
+    #[must_use]
+    pub fn into_inner(self) -> FileWatcher {
+        self.file_watcher
+    }
+
+    /// Waits for the next change, like `tokio::sync::mpsc::Receiver::recv`.
+    ///
+    /// Cancel safe, so it can be used as a branch in `tokio::select!`: if another branch
+    /// completes first, no change is lost.
+    pub async fn recv(&mut self) -> ChangeMessage {
+        poll_fn(|cx| self.poll_change(cx)).await
+    }
+
+    fn poll_change(&self, cx: &Context<'_>) -> Poll<ChangeMessage> {
+        // register before looking, so a change sent in between still wakes us up
+        self.file_watcher.notifier.register(cx.waker());
+        self.file_watcher
+            .next_change()
+            .map_or(Poll::Pending, Poll::Ready)
+    }
+}
+
+impl Stream for ChangeStream {
+    type Item = ChangeMessage;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_change(cx).map(Some)
+    }
+}
+
+impl FileWatcher {
+    #[must_use]
+    pub const fn into_stream(self) -> ChangeStream {
+        ChangeStream::new(self)
+    }
+}
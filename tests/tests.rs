@@ -1,9 +1,28 @@
+use fs_change_detector::{
+    Backend, ChangeMessage, DebounceMode, FileWatcher, FileWatcherError, RecursiveMode,
+    ReportedEvents, Snapshot, WatchOptions, WatchRoot, start_watch_with_options,
+};
+use std::io::Write;
+use std::time::{Duration, Instant};
 use tracing::{info, warn};
-use fs_change_detector::FileWatcher;
+
+fn wait_for_changes(file_watcher: &FileWatcher) -> Vec<ChangeMessage> {
+    let start = Instant::now();
+    let mut changes = Vec::new();
+    while start.elapsed() < Duration::from_secs(3) {
+        std::thread::sleep(Duration::from_millis(50));
+        changes.extend(file_watcher.take_changes());
+        if !changes.is_empty() {
+            std::thread::sleep(Duration::from_millis(200));
+            changes.extend(file_watcher.take_changes());
+            break;
+        }
+    }
+    changes
+}
 
 #[test_log::test]
 fn test() {
-
     let file_watcher = FileWatcher::new("./".as_ref()).unwrap();
 
     for _ in 0..15 {
@@ -14,4 +33,791 @@ fn test() {
             info!("...no change...");
         }
     }
-}
\ No newline at end of file
+}
+
+#[test_log::test]
+fn reports_modified_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("data.txt");
+    std::fs::write(&file, "first").unwrap();
+
+    let file_watcher = FileWatcher::new(dir.path()).unwrap();
+    std::fs::write(&file, "second").unwrap();
+
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Modified(file)),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn reports_created_and_removed_when_all_events_selected() {
+    let dir = tempfile::tempdir().unwrap();
+    let file_watcher = FileWatcher::builder(dir.path())
+        .events(ReportedEvents::All)
+        .build()
+        .unwrap();
+
+    let file = dir.path().join("new_shader.wgsl");
+    std::fs::write(&file, "fn main() {}").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Created(file.clone())),
+        "{changes:?}"
+    );
+
+    std::fs::remove_file(&file).unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Removed(file)),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn trailing_debounce_delivers_once_after_burst() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("chunked.txt");
+    std::fs::write(&file, "").unwrap();
+    let options = WatchOptions {
+        debounce: Duration::from_millis(300),
+        debounce_mode: DebounceMode::Trailing,
+        ..WatchOptions::default()
+    };
+    let file_watcher = FileWatcher::with_options(dir.path(), &options).unwrap();
+
+    for chunk in 0..5 {
+        std::fs::write(&file, format!("chunk {chunk}")).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(file_watcher.take_changes().is_empty());
+    }
+
+    let changes = wait_for_changes(&file_watcher);
+    assert_eq!(changes, vec![ChangeMessage::Modified(file)]);
+}
+
+#[test_log::test]
+fn longest_debounce_window_does_not_stop_the_watcher() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = dir.path().join("first.txt");
+    let second = dir.path().join("second.txt");
+    std::fs::write(&first, "").unwrap();
+    std::fs::write(&second, "").unwrap();
+    let file_watcher = FileWatcher::builder(dir.path())
+        .debounce(Duration::MAX)
+        .debounce_mode(DebounceMode::LeadingAndTrailing)
+        .build()
+        .unwrap();
+
+    std::fs::write(&first, "once").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Modified(first.clone())),
+        "{changes:?}"
+    );
+    // the repeat is held for the trailing edge, which never comes
+    std::fs::write(&first, "twice").unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+
+    std::fs::write(&second, "still watching").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Modified(second)),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn glob_filters_are_relative_to_watch_root() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("assets/textures")).unwrap();
+    std::fs::create_dir_all(dir.path().join("assets/target")).unwrap();
+    let texture = dir.path().join("assets/textures/grass.png");
+    let built = dir.path().join("assets/target/grass.png");
+    let notes = dir.path().join("assets/textures/notes.txt");
+
+    let file_watcher = FileWatcher::builder(dir.path())
+        .events(ReportedEvents::All)
+        .glob("assets/**/*.png")
+        .glob("!**/target/**")
+        .build()
+        .unwrap();
+
+    std::fs::write(&built, "ignored").unwrap();
+    std::fs::write(&notes, "ignored").unwrap();
+    std::fs::write(&texture, "pixels").unwrap();
+
+    let changes = wait_for_changes(&file_watcher);
+    assert!(!changes.is_empty());
+    assert!(
+        changes.iter().all(|change| change.path() == texture),
+        "{changes:?}"
+    );
+}
+
+#[test]
+fn invalid_glob_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let result = FileWatcher::builder(dir.path()).include("assets/[").build();
+    assert!(matches!(result, Err(FileWatcherError::InvalidGlob(_))));
+}
+
+#[test_log::test]
+fn ignore_files_suppress_changes_and_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("target")).unwrap();
+    std::fs::create_dir_all(dir.path().join("logs")).unwrap();
+    std::fs::write(dir.path().join(".gitignore"), "target/\n*.log\n").unwrap();
+    std::fs::write(dir.path().join("logs/.ignore"), "!important.log\n").unwrap();
+    let important = dir.path().join("logs/important.log");
+    let script = dir.path().join("main.rs");
+    let notes = dir.path().join("notes.txt");
+
+    let file_watcher = FileWatcher::builder(dir.path())
+        .events(ReportedEvents::All)
+        .respect_ignore_files(true)
+        .build()
+        .unwrap();
+
+    std::fs::write(dir.path().join("target/out.bin"), "ignored").unwrap();
+    std::fs::write(dir.path().join("logs/debug.log"), "ignored").unwrap();
+    std::fs::write(&important, "kept").unwrap();
+    std::fs::write(&script, "kept").unwrap();
+
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes
+            .iter()
+            .all(|change| change.path() == important || change.path() == script),
+        "{changes:?}"
+    );
+    assert!(
+        changes.contains(&ChangeMessage::Created(important)),
+        "{changes:?}"
+    );
+    assert!(
+        changes.contains(&ChangeMessage::Created(script)),
+        "{changes:?}"
+    );
+
+    std::fs::write(dir.path().join(".gitignore"), "target/\n*.log\n*.txt\n").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    let _ = file_watcher.take_changes();
+    std::fs::write(&notes, "ignored after reload").unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let changes = file_watcher.take_changes();
+    assert!(changes.is_empty(), "{changes:?}");
+}
+
+#[test_log::test]
+fn several_roots_share_one_watcher() {
+    let dir = tempfile::tempdir().unwrap();
+    let assets = dir.path().join("assets");
+    let scripts = dir.path().join("scripts");
+    let nested = assets.join("nested");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::create_dir_all(&scripts).unwrap();
+
+    let file_watcher = FileWatcher::builder(&assets)
+        .root(&scripts, RecursiveMode::NonRecursive)
+        .root(&nested, RecursiveMode::Recursive)
+        .events(ReportedEvents::All)
+        .debounce(Duration::ZERO)
+        .build()
+        .unwrap();
+
+    let script = scripts.join("main.lua");
+    let texture = nested.join("grass.png");
+    std::fs::write(&script, "print()").unwrap();
+    std::fs::write(&texture, "pixels").unwrap();
+
+    let changes = wait_for_changes(&file_watcher);
+    let created: Vec<_> = changes
+        .iter()
+        .filter(|change| matches!(change, ChangeMessage::Created(_)))
+        .collect();
+    assert_eq!(created.len(), 2, "{changes:?}");
+    assert_eq!(
+        file_watcher.root_of(&ChangeMessage::Created(script)),
+        Some(scripts.as_path())
+    );
+    assert_eq!(
+        file_watcher.root_of(&ChangeMessage::Created(texture)),
+        Some(nested.as_path())
+    );
+}
+
+#[test_log::test]
+fn roots_can_be_added_and_removed_at_runtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    std::fs::create_dir_all(&first).unwrap();
+    std::fs::create_dir_all(&second).unwrap();
+
+    let mut file_watcher = FileWatcher::builder(&first)
+        .events(ReportedEvents::All)
+        .build()
+        .unwrap();
+    file_watcher
+        .add_path(&second, RecursiveMode::Recursive)
+        .unwrap();
+
+    let file = second.join("project.toml");
+    std::fs::write(&file, "added").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Created(file.clone())),
+        "{changes:?}"
+    );
+
+    file_watcher.remove_path(&second).unwrap();
+    std::fs::write(&file, "not watched anymore").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(file_watcher.take_changes().is_empty());
+
+    assert!(matches!(
+        file_watcher.remove_path(&second),
+        Err(FileWatcherError::WatchNotFound(_))
+    ));
+}
+
+#[test_log::test]
+fn depth_limited_root_ignores_deeper_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    let deep = dir.path().join("one/two");
+    std::fs::create_dir_all(&deep).unwrap();
+
+    let file_watcher = FileWatcher::builder(dir.path())
+        .watch_root(WatchRoot::recursive(dir.path()).with_max_depth(1))
+        .events(ReportedEvents::All)
+        .build()
+        .unwrap();
+
+    std::fs::write(deep.join("too_deep.txt"), "ignored").unwrap();
+    let shallow_dir = dir.path().join("new");
+    std::fs::create_dir(&shallow_dir).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    let config = shallow_dir.join("config.toml");
+    std::fs::write(&config, "seen").unwrap();
+
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Created(config)),
+        "{changes:?}"
+    );
+    assert!(
+        changes
+            .iter()
+            .all(|change| !change.path().starts_with(&deep)),
+        "{changes:?}"
+    );
+}
+
+#[cfg(feature = "stream")]
+#[test_log::test]
+fn changes_are_available_as_a_stream() {
+    use futures::StreamExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("service.toml");
+    std::fs::write(&file, "port = 1").unwrap();
+    let mut stream = FileWatcher::new(dir.path()).unwrap().into_stream();
+
+    let writer_file = file.clone();
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        std::fs::write(&writer_file, "port = 2").unwrap();
+    });
+
+    let change = futures::executor::block_on(stream.next());
+    writer.join().unwrap();
+    assert_eq!(change, Some(ChangeMessage::Modified(file)));
+}
+
+#[test_log::test]
+fn wait_for_change_blocks_until_a_change_or_timeout() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("input.txt");
+    std::fs::write(&file, "before").unwrap();
+    let file_watcher = FileWatcher::new(dir.path()).unwrap();
+
+    let start = Instant::now();
+    assert!(
+        file_watcher
+            .wait_for_change_timeout(Duration::from_millis(200))
+            .is_empty()
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    let writer_file = file.clone();
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        std::fs::write(&writer_file, "after").unwrap();
+    });
+    let changes = file_watcher.wait_for_change();
+    writer.join().unwrap();
+    assert!(
+        changes.contains(&ChangeMessage::Modified(file)),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn scoped_handlers_run_for_matching_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let shader = dir.path().join("water.wgsl");
+    let script = dir.path().join("boat.lua");
+    std::fs::write(&shader, "").unwrap();
+    std::fs::write(&script, "").unwrap();
+    let mut file_watcher = FileWatcher::new(dir.path()).unwrap();
+
+    let (reload_sender, reload_receiver) = std::sync::mpsc::channel();
+    file_watcher
+        .on_change_matching("**/*.wgsl", move |change| {
+            reload_sender.send(change.clone()).unwrap();
+        })
+        .unwrap();
+
+    std::fs::write(&script, "print()").unwrap();
+    std::fs::write(&shader, "fn main() {}").unwrap();
+
+    let reloaded = reload_receiver
+        .recv_timeout(Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(reloaded, ChangeMessage::Modified(shader));
+    assert!(
+        reload_receiver
+            .recv_timeout(Duration::from_millis(300))
+            .is_err()
+    );
+}
+
+#[test_log::test]
+fn poll_backend_reports_modifications() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("mounted.cfg");
+    std::fs::write(&file, "a = 1").unwrap();
+
+    let file_watcher = FileWatcher::builder(dir.path())
+        .backend(Backend::Poll {
+            interval: Duration::from_millis(50),
+            compare_contents: true,
+        })
+        .build()
+        .unwrap();
+    assert!(file_watcher.is_polling());
+
+    std::fs::write(&file, "a = 2").unwrap();
+    let changes = file_watcher.wait_for_change_timeout(Duration::from_secs(3));
+    assert!(
+        changes.contains(&ChangeMessage::Modified(file)),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn content_hashing_suppresses_saves_without_edits() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("mesh.obj");
+    std::fs::write(&file, "v 0 0 0").unwrap();
+
+    let file_watcher = FileWatcher::builder(dir.path())
+        .hash_contents(1024 * 1024)
+        .debounce(Duration::ZERO)
+        .build()
+        .unwrap();
+
+    std::fs::write(&file, "v 1 0 0").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Modified(file.clone())),
+        "{changes:?}"
+    );
+
+    std::fs::write(&file, "v 1 0 0").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    let changes = file_watcher.take_changes();
+    assert!(changes.is_empty(), "{changes:?}");
+
+    // truncated, and written back in two chunks
+    let mut saved = std::fs::File::create(&file).unwrap();
+    saved.write_all(b"v 1 ").unwrap();
+    saved.sync_all().unwrap();
+    saved.write_all(b"0 0").unwrap();
+    drop(saved);
+    std::thread::sleep(Duration::from_millis(300));
+    let changes = file_watcher.take_changes();
+    assert!(changes.is_empty(), "{changes:?}");
+}
+
+#[test]
+fn snapshot_diff_finds_added_removed_modified_and_renamed() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    std::fs::write(root.join("kept.txt"), "same").unwrap();
+    std::fs::write(root.join("edited.txt"), "before").unwrap();
+    std::fs::write(root.join("deleted.txt"), "gone soon").unwrap();
+    std::fs::write(root.join("old_name.txt"), "moving").unwrap();
+
+    let before = Snapshot::capture_with_hashes(root, 1024).unwrap();
+
+    std::fs::write(root.join("edited.txt"), "after, longer").unwrap();
+    std::fs::remove_file(root.join("deleted.txt")).unwrap();
+    std::fs::rename(root.join("old_name.txt"), root.join("new_name.txt")).unwrap();
+    std::fs::write(root.join("added.txt"), "new").unwrap();
+
+    let after = Snapshot::capture_with_hashes(root, 1024).unwrap();
+    let diff = before.diff(&after);
+
+    assert_eq!(diff.added, vec![root.join("added.txt")]);
+    assert_eq!(diff.removed, vec![root.join("deleted.txt")]);
+    assert_eq!(diff.modified, vec![root.join("edited.txt")]);
+    assert_eq!(
+        diff.renamed,
+        vec![(root.join("old_name.txt"), root.join("new_name.txt"))]
+    );
+    assert!(after.diff(&after).is_empty());
+}
+
+#[test_log::test]
+fn state_file_reports_changes_made_while_not_running() {
+    let dir = tempfile::tempdir().unwrap();
+    let state_dir = tempfile::tempdir().unwrap();
+    let state_file = state_dir.path().join("watch.state");
+    let root = dir.path();
+    std::fs::write(root.join("edited.txt"), "before").unwrap();
+    std::fs::write(root.join("deleted.txt"), "gone soon").unwrap();
+
+    let build = || {
+        FileWatcher::builder(root)
+            .events(ReportedEvents::All)
+            .state_file(&state_file)
+            .build()
+            .unwrap()
+    };
+
+    let first = build();
+    assert!(first.take_changes().is_empty());
+    drop(first);
+
+    std::fs::write(root.join("edited.txt"), "after, longer").unwrap();
+    std::fs::remove_file(root.join("deleted.txt")).unwrap();
+    std::fs::write(root.join("added.txt"), "new").unwrap();
+
+    let second = build();
+    let mut changes = second.take_changes();
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    assert_eq!(
+        changes,
+        vec![
+            ChangeMessage::Created(root.join("added.txt")),
+            ChangeMessage::Removed(root.join("deleted.txt")),
+            ChangeMessage::Modified(root.join("edited.txt")),
+        ]
+    );
+
+    std::fs::write(root.join("edited.txt"), "live").unwrap();
+    let changes = wait_for_changes(&second);
+    assert!(
+        changes.contains(&ChangeMessage::Modified(root.join("edited.txt"))),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn batch_mode_delivers_net_change_per_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    std::fs::write(root.join("edited.txt"), "before").unwrap();
+
+    let file_watcher = FileWatcher::builder(root)
+        .events(ReportedEvents::All)
+        .debounce(Duration::from_millis(300))
+        .debounce_mode(DebounceMode::Batch)
+        .build()
+        .unwrap();
+
+    std::fs::write(root.join("new.txt"), "one").unwrap();
+    std::fs::write(root.join("new.txt"), "two").unwrap();
+    std::fs::write(root.join("temp.txt"), "short lived").unwrap();
+    std::fs::remove_file(root.join("temp.txt")).unwrap();
+    std::fs::write(root.join("edited.txt"), "after").unwrap();
+    std::fs::write(root.join("edited.txt"), "after again").unwrap();
+
+    let mut batch = file_watcher
+        .wait_for_batch_timeout(Duration::from_secs(3))
+        .unwrap();
+    batch.sort_by(|a, b| a.path().cmp(b.path()));
+    assert_eq!(
+        batch,
+        vec![
+            ChangeMessage::Modified(root.join("edited.txt")),
+            ChangeMessage::Created(root.join("new.txt")),
+        ]
+    );
+    assert!(file_watcher.take_batch().is_none());
+}
+
+#[test_log::test]
+fn batch_mode_reaches_the_plain_receiver_in_first_touch_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let options = WatchOptions {
+        events: ReportedEvents::All,
+        debounce: Duration::from_millis(300),
+        debounce_mode: DebounceMode::Batch,
+        ..WatchOptions::default()
+    };
+    let (_watcher, receiver) = start_watch_with_options(root, &options).unwrap();
+
+    std::fs::write(root.join("a.txt"), "one").unwrap();
+    std::fs::write(root.join("b.txt"), "one").unwrap();
+    std::fs::write(root.join("a.txt"), "two").unwrap();
+
+    let start = Instant::now();
+    let mut changes = Vec::new();
+    while changes.len() < 2 && start.elapsed() < Duration::from_secs(3) {
+        std::thread::sleep(Duration::from_millis(50));
+        while let Ok(change) = receiver.recv() {
+            changes.push(change);
+        }
+    }
+    assert_eq!(
+        changes,
+        vec![
+            ChangeMessage::Created(root.join("a.txt")),
+            ChangeMessage::Created(root.join("b.txt")),
+        ]
+    );
+}
+
+#[test_log::test]
+fn renames_are_paired_and_moves_across_the_tree_boundary_are_created_or_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    std::fs::create_dir(root.join("sub")).unwrap();
+    std::fs::write(root.join("a.txt"), "a").unwrap();
+    std::fs::write(root.join("leaving.txt"), "bye").unwrap();
+    std::fs::write(outside.path().join("arriving.txt"), "hi").unwrap();
+
+    let file_watcher = FileWatcher::builder(root)
+        .events(ReportedEvents::All)
+        .debounce(Duration::ZERO)
+        .build()
+        .unwrap();
+
+    std::fs::rename(root.join("a.txt"), root.join("sub/b.txt")).unwrap();
+    std::fs::rename(root.join("leaving.txt"), outside.path().join("leaving.txt")).unwrap();
+    std::fs::rename(
+        outside.path().join("arriving.txt"),
+        root.join("arriving.txt"),
+    )
+    .unwrap();
+
+    std::thread::sleep(Duration::from_millis(500));
+    let changes = file_watcher.take_changes();
+    assert_eq!(
+        changes,
+        vec![
+            ChangeMessage::Renamed {
+                from: root.join("a.txt"),
+                to: root.join("sub/b.txt"),
+            },
+            ChangeMessage::Created(root.join("arriving.txt")),
+            ChangeMessage::Removed(root.join("leaving.txt")),
+        ]
+    );
+}
+
+#[test_log::test]
+fn atomic_saves_are_reported_as_one_modification() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let vim_file = root.join("main.rs");
+    let jetbrains_file = root.join("lib.rs");
+    std::fs::write(&vim_file, "fn main() {}").unwrap();
+    std::fs::write(&jetbrains_file, "pub fn f() {}").unwrap();
+
+    let file_watcher = FileWatcher::builder(root)
+        .debounce(Duration::ZERO)
+        .detect_atomic_saves(true)
+        .build()
+        .unwrap();
+
+    // vim: probe, move the original aside as backup, write it anew, drop the backup
+    std::fs::write(root.join("4913"), "").unwrap();
+    std::fs::remove_file(root.join("4913")).unwrap();
+    std::fs::rename(&vim_file, root.join("main.rs~")).unwrap();
+    std::fs::write(&vim_file, "fn main() { run(); }").unwrap();
+    std::fs::remove_file(root.join("main.rs~")).unwrap();
+
+    // JetBrains: write a temporary file and rename it over the original
+    let temporary = root.join("lib.rs___jb_tmp___");
+    std::fs::write(&temporary, "pub fn g() {}").unwrap();
+    std::fs::rename(&temporary, &jetbrains_file).unwrap();
+
+    std::thread::sleep(Duration::from_millis(500));
+    let changes = file_watcher.take_changes();
+    assert_eq!(
+        changes,
+        vec![
+            ChangeMessage::Modified(vim_file),
+            ChangeMessage::Modified(jetbrains_file),
+        ]
+    );
+}
+
+#[test_log::test]
+fn scoped_queries_leave_other_changes_pending() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let textures = root.join("textures");
+    let scripts = root.join("scripts");
+    std::fs::create_dir(&textures).unwrap();
+    std::fs::create_dir(&scripts).unwrap();
+    std::fs::write(textures.join("grass.png"), "green").unwrap();
+    std::fs::write(scripts.join("main.lua"), "print(1)").unwrap();
+    std::fs::write(root.join("notes.txt"), "todo").unwrap();
+
+    // the default window folds the truncate and the write of each file into one change
+    let file_watcher = FileWatcher::new(root).unwrap();
+
+    std::fs::write(textures.join("grass.png"), "greener").unwrap();
+    std::fs::write(scripts.join("main.lua"), "print(2)").unwrap();
+    std::fs::write(root.join("notes.txt"), "done").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+
+    assert!(file_watcher.has_changed_under(&textures));
+    assert!(!file_watcher.has_changed_under(&textures));
+    assert_eq!(
+        file_watcher.take_changes_matching("**/*.lua").unwrap(),
+        vec![ChangeMessage::Modified(scripts.join("main.lua"))]
+    );
+    assert_eq!(
+        file_watcher.take_changes(),
+        vec![ChangeMessage::Modified(root.join("notes.txt"))]
+    );
+}
+
+#[test_log::test]
+fn background_errors_are_reported_to_the_caller() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("watched");
+    std::fs::create_dir(&root).unwrap();
+
+    let file_watcher = FileWatcher::builder(&root)
+        .backend(Backend::Poll {
+            interval: Duration::from_millis(50),
+            compare_contents: false,
+        })
+        .build()
+        .unwrap();
+    assert!(file_watcher.take_errors().is_empty());
+
+    std::fs::remove_dir(&root).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+
+    let errors = file_watcher.take_errors();
+    assert!(
+        errors
+            .iter()
+            .any(|error| matches!(error, FileWatcherError::IoError(_))),
+        "{errors:?}"
+    );
+}
+
+#[test_log::test]
+fn removed_root_is_reported_and_watched_again_when_recreated() {
+    let dir = tempfile::tempdir().unwrap();
+    let build = dir.path().join("build");
+    std::fs::create_dir(&build).unwrap();
+
+    let file_watcher = FileWatcher::builder(&build)
+        .events(ReportedEvents::All)
+        .debounce(Duration::ZERO)
+        .rewatch_removed_roots(true)
+        .build()
+        .unwrap();
+
+    std::fs::remove_dir_all(&build).unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert_eq!(changes, vec![ChangeMessage::RootRemoved(build.clone())]);
+
+    std::fs::create_dir(&build).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let changes = file_watcher.take_changes();
+    assert_eq!(changes, vec![ChangeMessage::Created(build.clone())]);
+
+    std::fs::write(build.join("out.o"), "elf").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Created(build.join("out.o"))),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn root_recreated_right_after_removal_is_watched_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let build = dir.path().join("build");
+    std::fs::create_dir(&build).unwrap();
+    // enough to still be handling their removals when the root is created again
+    for index in 0..500 {
+        std::fs::write(build.join(format!("{index}.o")), "elf").unwrap();
+    }
+
+    let file_watcher = FileWatcher::builder(&build)
+        .events(ReportedEvents::All)
+        .debounce(Duration::ZERO)
+        .rewatch_removed_roots(true)
+        .build()
+        .unwrap();
+
+    std::fs::remove_dir_all(&build).unwrap();
+    std::fs::create_dir(&build).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let changes = file_watcher.take_changes();
+    assert!(
+        changes.ends_with(&[
+            ChangeMessage::RootRemoved(build.clone()),
+            ChangeMessage::Created(build.clone())
+        ]),
+        "{changes:?}"
+    );
+
+    std::fs::write(build.join("out.o"), "elf").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Created(build.join("out.o"))),
+        "{changes:?}"
+    );
+}
+
+#[test_log::test]
+fn missing_root_is_watched_once_it_is_created() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("out").join("config.toml");
+
+    let file_watcher = FileWatcher::builder(&config)
+        .events(ReportedEvents::All)
+        .debounce(Duration::ZERO)
+        .wait_for_missing_roots(true)
+        .build()
+        .unwrap();
+
+    std::fs::create_dir(dir.path().join("out")).unwrap();
+    std::fs::write(dir.path().join("out").join("other.toml"), "").unwrap();
+    std::fs::write(&config, "").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert_eq!(changes, vec![ChangeMessage::Created(config.clone())]);
+
+    std::fs::write(&config, "verbose = true").unwrap();
+    let changes = wait_for_changes(&file_watcher);
+    assert!(
+        changes.contains(&ChangeMessage::Modified(config.clone())),
+        "{changes:?}"
+    );
+}